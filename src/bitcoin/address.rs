@@ -0,0 +1,332 @@
+// Derive addresses from output script (scriptPubKey), so we do not depend
+// from `addresses` in bitcoind JSON (removed in Bitcoin Core 22).
+//
+// For P2PK and bare multisig addresses are P2PKH addresses of public keys,
+// same as bitcoind returned before.
+
+use bech32::{u5, ToBase32 as _, Variant};
+use ripemd160::Ripemd160;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub struct AddressPrefixes {
+    pub pubkey_hash: u8,
+    pub script_hash: u8,
+    // Segwit addresses, only if coin have segwit
+    pub bech32_hrp: Option<&'static str>,
+    // Bitcoin Cash use CashAddr instead of base58 addresses
+    pub cashaddr_prefix: Option<&'static str>,
+}
+
+impl AddressPrefixes {
+    // Return addresses for output script, empty for unknown scripts
+    pub fn get_addresses(&self, script: &[u8]) -> Vec<String> {
+        match script {
+            // P2PKH: OP_DUP OP_HASH160 <20> OP_EQUALVERIFY OP_CHECKSIG
+            [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+                vec![self.pubkey_hash_address(hash)]
+            }
+            // P2SH: OP_HASH160 <20> OP_EQUAL
+            [0xa9, 0x14, hash @ .., 0x87] if hash.len() == 20 => match self.cashaddr_prefix {
+                Some(prefix) => vec![cashaddr(prefix, CASHADDR_SCRIPT_HASH, hash)],
+                None => vec![self.base58check(self.script_hash, hash)],
+            },
+            // P2SH32 (Bitcoin Cash only): OP_HASH256 <32> OP_EQUAL
+            [0xaa, 0x20, hash @ .., 0x87] if hash.len() == 32 => match self.cashaddr_prefix {
+                Some(prefix) => vec![cashaddr(prefix, CASHADDR_SCRIPT_HASH, hash)],
+                None => vec![],
+            },
+            // Witness program: OP_0..OP_16 <2..40>
+            [version @ (0x00 | 0x51..=0x60), len, program @ ..]
+                if *len as usize == program.len() && (2..=40).contains(len) =>
+            {
+                let version = if *version == 0 { 0 } else { version - 0x50 };
+                self.segwit(version, program).into_iter().collect()
+            }
+            // P2PK: <pubkey> OP_CHECKSIG
+            [len, pubkey @ .., 0xac] if *len as usize == pubkey.len() && is_pubkey(pubkey) => {
+                vec![self.pubkey_hash_address(&hash160(pubkey))]
+            }
+            // Multisig: OP_m <pubkeys> OP_n OP_CHECKMULTISIG
+            [m @ 0x51..=0x60, pubkeys @ .., n @ 0x51..=0x60, 0xae] if m <= n => {
+                self.multisig(pubkeys, (n - 0x50) as usize)
+            }
+            _ => vec![],
+        }
+    }
+
+    fn pubkey_hash_address(&self, hash: &[u8]) -> String {
+        match self.cashaddr_prefix {
+            Some(prefix) => cashaddr(prefix, CASHADDR_PUBKEY_HASH, hash),
+            None => self.base58check(self.pubkey_hash, hash),
+        }
+    }
+
+    fn base58check(&self, prefix: u8, hash: &[u8]) -> String {
+        let mut data = Vec::with_capacity(1 + hash.len());
+        data.push(prefix);
+        data.extend_from_slice(hash);
+        bs58::encode(data).with_check().into_string()
+    }
+
+    // Segwit v0 use bech32 (BIP173), v1+ bech32m (BIP350)
+    fn segwit(&self, version: u8, program: &[u8]) -> Option<String> {
+        let hrp = self.bech32_hrp?;
+        let variant = match version {
+            0 if program.len() == 20 || program.len() == 32 => Variant::Bech32,
+            0 => return None,
+            _ => Variant::Bech32m,
+        };
+
+        let mut data = vec![u5::try_from_u8(version).ok()?];
+        data.extend(program.to_base32());
+        bech32::encode(hrp, data, variant).ok()
+    }
+
+    fn multisig(&self, mut pubkeys: &[u8], count: usize) -> Vec<String> {
+        let mut addresses = Vec::with_capacity(count);
+        while let Some((&len, rest)) = pubkeys.split_first() {
+            let len = len as usize;
+            if rest.len() < len || !is_pubkey(&rest[..len]) {
+                return vec![];
+            }
+
+            addresses.push(self.pubkey_hash_address(&hash160(&rest[..len])));
+            pubkeys = &rest[len..];
+        }
+
+        if addresses.len() == count {
+            addresses
+        } else {
+            vec![]
+        }
+    }
+}
+
+// CashAddr type bits of version byte
+static CASHADDR_PUBKEY_HASH: u8 = 0;
+static CASHADDR_SCRIPT_HASH: u8 = 1;
+
+// CashAddr: `prefix:payload`, where payload is base32 of version byte and
+// hash with 40 bits checksum (BCH code, not same as bech32).
+// See https://github.com/bitcoincashorg/bitcoincash.org/blob/master/spec/cashaddr.md
+fn cashaddr(prefix: &str, kind: u8, hash: &[u8]) -> String {
+    static CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+    let size_bits = match hash.len() {
+        20 => 0,
+        24 => 1,
+        28 => 2,
+        32 => 3,
+        40 => 4,
+        48 => 5,
+        56 => 6,
+        _ => 7,
+    };
+    let mut payload = vec![(kind << 3) | size_bits];
+    payload.extend_from_slice(hash);
+    let mut data = payload
+        .to_base32()
+        .into_iter()
+        .map(|value| value.to_u8())
+        .collect::<Vec<u8>>();
+
+    let mut values = prefix.bytes().map(|c| c & 0x1f).collect::<Vec<u8>>();
+    values.push(0);
+    values.extend_from_slice(&data);
+    values.extend_from_slice(&[0; 8]);
+    let checksum = cashaddr_polymod(&values);
+    data.extend((0..8).map(|i| ((checksum >> (5 * (7 - i))) & 0x1f) as u8));
+
+    let mut address = format!("{}:", prefix);
+    address.extend(
+        data.into_iter()
+            .map(|value| CHARSET[value as usize] as char),
+    );
+    address
+}
+
+fn cashaddr_polymod(values: &[u8]) -> u64 {
+    static GENERATORS: [u64; 5] = [
+        0x98_f2bc_8e61,
+        0x79_b76d_99e2,
+        0xf3_3e5f_b3c4,
+        0xae_2eab_e2a8,
+        0x1e_4f43_e470,
+    ];
+
+    let mut checksum = 1;
+    for value in values {
+        let top = checksum >> 35;
+        checksum = ((checksum & 0x07_ffff_ffff) << 5) ^ *value as u64;
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum ^ 1
+}
+
+// Check only size by first byte, like `CPubKey::ValidSize` in bitcoind
+fn is_pubkey(data: &[u8]) -> bool {
+    match data.first() {
+        Some(0x02) | Some(0x03) => data.len() == 33,
+        Some(0x04) | Some(0x06) | Some(0x07) => data.len() == 65,
+        _ => false,
+    }
+}
+
+fn hash160(data: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(&Sha256::digest(data)).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitcoin::params::CoinParams;
+
+    fn addresses(chain: &str, script: &str) -> Vec<String> {
+        coin_addresses("bitcoin", chain, script)
+    }
+
+    fn coin_addresses(coin: &str, chain: &str, script: &str) -> Vec<String> {
+        let (_coin, chain) = CoinParams::get(coin, chain).unwrap();
+        let prefixes = &chain.address_prefixes;
+        prefixes.get_addresses(&hex::decode(script).unwrap())
+    }
+
+    static GENESIS_PUBKEY: &str = "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+
+    #[test]
+    fn address_p2pkh() {
+        let script = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac";
+        let expected = vec!["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"];
+        assert_eq!(addresses("main", script), expected);
+    }
+
+    #[test]
+    fn address_p2pk() {
+        let script = format!("41{}ac", GENESIS_PUBKEY);
+        let expected = vec!["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"];
+        assert_eq!(addresses("main", &script), expected);
+    }
+
+    #[test]
+    fn address_multisig() {
+        let script = format!("5141{}41{}52ae", GENESIS_PUBKEY, GENESIS_PUBKEY);
+        let expected = vec!["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"; 2];
+        assert_eq!(addresses("main", &script), expected);
+
+        let script = format!("5141{}53ae", GENESIS_PUBKEY);
+        assert!(addresses("main", &script).is_empty());
+    }
+
+    #[test]
+    fn address_p2sh() {
+        let hash = "62e907b15cbf27d5425399ebf6f0fb50ebb88f18";
+        for (chain, prefix) in &[("main", 0x05), ("test", 0xc4)] {
+            let address = addresses(chain, &format!("a914{}87", hash)).pop().unwrap();
+            let decoded = bs58::decode(&address).with_check(None).into_vec().unwrap();
+            assert_eq!(decoded[0], *prefix);
+            assert_eq!(hex::encode(&decoded[1..]), hash);
+        }
+    }
+
+    #[test]
+    fn address_segwit() {
+        // BIP173 and BIP350 test vectors
+        let script = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+        let expected = vec!["bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"];
+        assert_eq!(addresses("main", script), expected);
+
+        let script = "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262";
+        let expected = vec!["tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"];
+        assert_eq!(addresses("test", script), expected);
+
+        let script =
+            "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6";
+        let expected =
+            vec!["bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y"];
+        assert_eq!(addresses("main", script), expected);
+
+        let script = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+        let address = addresses("regtest", script).pop().unwrap();
+        assert!(address.starts_with("bcrt1q"));
+
+        for chain in &["testnet4", "signet"] {
+            let address = addresses(chain, script).pop().unwrap();
+            assert!(address.starts_with("tb1q"));
+        }
+    }
+
+    #[test]
+    fn address_unknown() {
+        assert!(addresses("main", "").is_empty());
+        assert!(addresses("main", "6a0401020304").is_empty()); // OP_RETURN
+        assert!(addresses("main", "0013751e76e8199196d454941c45d1b3a323f1433b").is_empty());
+    }
+
+    #[test]
+    fn address_coins_base58() {
+        let hash = "62e907b15cbf27d5425399ebf6f0fb50ebb88f18";
+        let p2pkh = format!("76a914{}88ac", hash);
+        let p2sh = format!("a914{}87", hash);
+        for (coin, script, expected) in &[
+            ("litecoin", &p2pkh, "LUEweDxDA4WhvWiNXXSxjM9CYzHPJv4QQF"),
+            ("litecoin", &p2sh, "MGv9cSYnaRSTZNzYaN7bhbgmozoGkKBvCn"),
+            ("dogecoin", &p2pkh, "DEA5vGb2NpAwCiCp5yTE16F3DueQUVivQp"),
+            ("dogecoin", &p2sh, "A1TG3QCihNTvfF67tcng864kBsarnaPyFm"),
+        ] {
+            assert_eq!(coin_addresses(coin, "main", script), vec![*expected]);
+        }
+    }
+
+    #[test]
+    fn address_coins_segwit() {
+        let script = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+        let address = coin_addresses("litecoin", "main", script).pop().unwrap();
+        assert!(address.starts_with("ltc1q"));
+
+        assert!(coin_addresses("dogecoin", "main", script).is_empty());
+        assert!(coin_addresses("bitcoincash", "main", script).is_empty());
+    }
+
+    #[test]
+    fn address_cashaddr() {
+        // Test vectors from CashAddr spec, legacy address => cashaddr
+        for (legacy, expected) in &[
+            (
+                "1BpEi6DfDAUFd7GtittLSdBeYJvcoaVggu",
+                "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a",
+            ),
+            (
+                "1KXrWXciRDZUpQwQmuM1DbwsKDLYAYsVLR",
+                "bitcoincash:qr95sy3j9xwd2ap32xkykttr4cvcu7as4y0qverfuy",
+            ),
+            (
+                "3CWFddi6m4ndiGyKqzYvsFYagqDLPVMTzC",
+                "bitcoincash:ppm2qsznhks23z7629mms6s4cwef74vcwvn0h829pq",
+            ),
+        ] {
+            let decoded = bs58::decode(legacy).with_check(None).into_vec().unwrap();
+            let hash = hex::encode(&decoded[1..]);
+            let script = if decoded[0] == 0x00 {
+                format!("76a914{}88ac", hash)
+            } else {
+                format!("a914{}87", hash)
+            };
+            assert_eq!(
+                coin_addresses("bitcoincash", "main", &script),
+                vec![*expected]
+            );
+        }
+
+        // P2SH32
+        let script = format!("aa20{}87", "00".repeat(32));
+        let address = coin_addresses("bitcoincash", "main", &script)
+            .pop()
+            .unwrap();
+        assert!(address.starts_with("bitcoincash:p"));
+        assert!(addresses("main", &script).is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+// Amount in satoshis. Parsed exactly from decimal JSON numbers (without
+// floating point) and stored in PostgreSQL as `int8`.
+// Sums over many transactions (block totals, address stats) are `AmountSum`,
+// stored as `numeric`, because can be greater than `int8`.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::AddAssign;
+
+use bytes::{BufMut, BytesMut};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio_postgres::types::{accepts, to_sql_checked, IsNull, ToSql, Type};
+
+use crate::error::CustomError;
+use crate::AnyResult;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    // Should fit to `int8`
+    pub const MAX: Amount = Amount(i64::MAX as u64);
+
+    pub fn from_sat(value: u64) -> Option<Amount> {
+        if value <= Amount::MAX.0 {
+            Some(Amount(value))
+        } else {
+            None
+        }
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        Amount::from_sat(self.0.checked_add(other.0)?)
+    }
+
+    // Sum in one transaction, limited by `MAX_MONEY` in consensus, but data
+    // from node should not cause panic
+    pub fn try_add(self, other: Amount) -> AnyResult<Amount> {
+        self.checked_add(other).ok_or_else(|| {
+            let msg = format!("Amount overflow: {} + {}", self, other);
+            CustomError::new_any(msg)
+        })
+    }
+
+    // Parse decimal value in coins (like "0.00010000")
+    pub fn from_btc_str(value: &str) -> Option<Amount> {
+        let (int, frac) = match value.find('.') {
+            Some(pos) => (&value[0..pos], &value[pos + 1..]),
+            None => (value, ""),
+        };
+
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if int.is_empty() || frac.len() > 8 || !is_digits(int) || !is_digits(frac) {
+            return None;
+        }
+
+        let int = int.parse::<u64>().ok()?;
+        let frac = format!("{:0<8}", frac).parse::<u64>().ok()?;
+        Amount::from_sat(int.checked_mul(100_000_000)?.checked_add(frac)?)
+    }
+
+    // Deserialize JSON number in coins, like `value` in bitcoind output.
+    // Require `serde_json` with feature `arbitrary_precision`.
+    pub fn deserialize_btc<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Amount;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a JSON number with amount")
+            }
+
+            fn visit_map<V>(self, mut visitor: V) -> Result<Amount, V::Error>
+            where
+                V: de::MapAccess<'de>,
+            {
+                let value = visitor.next_key::<String>()?;
+                if value.is_none() {
+                    return Err(de::Error::invalid_type(de::Unexpected::Map, &self));
+                }
+
+                let value = visitor.next_value::<String>()?;
+                Amount::from_btc_str(&value)
+                    .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(&value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:08}", self.0 / 100_000_000, self.0 % 100_000_000)
+    }
+}
+
+// Serialized as satoshis
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        let value = u64::deserialize(deserializer)?;
+        Amount::from_sat(value).ok_or_else(|| {
+            de::Error::invalid_value(de::Unexpected::Unsigned(value), &"amount in satoshis")
+        })
+    }
+}
+
+impl ToSql for Amount {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        (self.0 as i64).to_sql(ty, out)
+    }
+
+    accepts!(INT8);
+
+    to_sql_checked!();
+}
+
+// Sum of amounts from many transactions. Total supply of Dogecoin is greater
+// than `i64::MAX`, so total received by address can be greater too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AmountSum(u128);
+
+impl AmountSum {
+    pub const ZERO: AmountSum = AmountSum(0);
+}
+
+impl From<Amount> for AmountSum {
+    fn from(amount: Amount) -> AmountSum {
+        AmountSum(amount.0 as u128)
+    }
+}
+
+// Values are `u64`, so overflow of `u128` is not possible
+impl AddAssign<Amount> for AmountSum {
+    fn add_assign(&mut self, other: Amount) {
+        self.0 += other.0 as u128;
+    }
+}
+
+// `numeric` binary format: number of digits, weight of first digit, sign,
+// display scale and digits in base 10000 (trailing zero digits omitted)
+impl ToSql for AmountSum {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let mut digits = vec![];
+        let mut value = self.0;
+        while value > 0 {
+            digits.push((value % 10_000) as i16);
+            value /= 10_000;
+        }
+        let weight = digits.len().saturating_sub(1) as i16;
+        digits.reverse();
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+
+        out.put_i16(digits.len() as i16);
+        out.put_i16(weight);
+        out.put_u16(0); // positive
+        out.put_u16(0); // no fractional part
+        for digit in digits {
+            out.put_i16(digit);
+        }
+        Ok(IsNull::No)
+    }
+
+    accepts!(NUMERIC);
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Output {
+        #[serde(deserialize_with = "Amount::deserialize_btc")]
+        value: Amount,
+    }
+
+    fn parse(value: &str) -> Option<u64> {
+        Amount::from_btc_str(value).map(|amount| amount.0)
+    }
+
+    #[test]
+    fn amount_from_btc_str() {
+        assert_eq!(parse("50.00000000"), Some(5_000_000_000));
+        assert_eq!(parse("0.0001"), Some(10_000));
+        assert_eq!(parse("21000000"), Some(2_100_000_000_000_000));
+        assert_eq!(parse("92233720368.54775807"), Some(i64::MAX as u64));
+
+        assert_eq!(parse("92233720368.54775808"), None);
+        assert_eq!(parse("0.000000001"), None);
+        assert_eq!(parse("-1.0"), None);
+        assert_eq!(parse("1e-8"), None);
+        assert_eq!(parse(".1"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn amount_try_add() {
+        let sum = Amount(1).try_add(Amount(2)).unwrap();
+        assert_eq!(sum, Amount(3));
+        assert_eq!(Amount::MAX.try_add(Amount::ZERO).unwrap(), Amount::MAX);
+
+        let error = Amount::MAX.try_add(Amount(1)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Amount overflow: 92233720368.54775807 + 0.00000001"
+        );
+    }
+
+    #[test]
+    fn amount_sum_to_sql() {
+        let encode = |value: u128| {
+            let mut out = BytesMut::new();
+            AmountSum(value).to_sql(&Type::NUMERIC, &mut out).unwrap();
+            out.chunks(2)
+                .map(|chunk| i16::from_be_bytes([chunk[0], chunk[1]]))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(encode(0), vec![0, 0, 0, 0]);
+        assert_eq!(encode(1_2345_6789), vec![3, 2, 0, 0, 1, 2345, 6789]);
+        assert_eq!(encode(5_0000_0000), vec![1, 2, 0, 0, 5]);
+        assert_eq!(encode(1_0000_0001), vec![3, 2, 0, 0, 1, 0, 1]);
+
+        let mut sum = AmountSum::from(Amount::MAX);
+        sum += Amount::MAX;
+        assert_eq!(sum, AmountSum(i64::MAX as u128 * 2));
+    }
+
+    #[test]
+    fn amount_deserialize() {
+        let output: Output = serde_json::from_str(r#"{"value": 0.00012345}"#).unwrap();
+        assert_eq!(output.value, Amount(12_345));
+        assert_eq!(output.value.to_string(), "0.00012345");
+
+        assert!(serde_json::from_str::<Output>(r#"{"value": 1.123456789}"#).is_err());
+        assert!(serde_json::from_str::<Output>(r#"{"value": -0.1}"#).is_err());
+        assert!(serde_json::from_str::<Output>(r#"{"value": "0.1"}"#).is_err());
+
+        let value: Amount = serde_json::from_str("12345").unwrap();
+        assert_eq!(value, Amount(12_345));
+        assert_eq!(serde_json::to_string(&value).unwrap(), "12345");
+        assert!(serde_json::from_str::<Amount>("18446744073709551615").is_err());
+    }
+}
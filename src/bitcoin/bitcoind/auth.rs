@@ -0,0 +1,121 @@
+// Authorization for bitcoind RPC: username/password from URL (also works for
+// `rpcauth`) or `.cookie` file, which is rewritten on every bitcoind start.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use base64::write::EncoderWriter as Base64Encoder;
+use reqwest::header::HeaderValue;
+
+use super::error::{BitcoindError, BitcoindResult};
+
+#[derive(Debug)]
+pub enum RPCAuth {
+    UserPass(HeaderValue),
+    // Cookie read on first request and re-read on HTTP 401
+    Cookie {
+        path: PathBuf,
+        header: RwLock<Option<HeaderValue>>,
+    },
+}
+
+impl RPCAuth {
+    pub fn user_pass(username: &str, password: Option<&str>) -> RPCAuth {
+        RPCAuth::UserPass(basic_header(username, password.unwrap_or("")))
+    }
+
+    pub fn cookie(path: &str) -> RPCAuth {
+        RPCAuth::Cookie {
+            path: PathBuf::from(path),
+            header: RwLock::new(None),
+        }
+    }
+
+    pub fn header(&self) -> BitcoindResult<HeaderValue> {
+        match self {
+            RPCAuth::UserPass(header) => Ok(header.clone()),
+            RPCAuth::Cookie { header, .. } => {
+                // Clone before reload, otherwise read lock is held
+                let value = header.read().unwrap().clone();
+                match value {
+                    Some(value) => Ok(value),
+                    None => self.reload().map(|value| value.unwrap()),
+                }
+            }
+        }
+    }
+
+    // Read cookie file again, `None` if auth is not changeable
+    pub fn reload(&self) -> BitcoindResult<Option<HeaderValue>> {
+        match self {
+            RPCAuth::UserPass(_) => Ok(None),
+            RPCAuth::Cookie { path, header } => {
+                let content =
+                    fs::read_to_string(path).map_err(|e| BitcoindError::Cookie(path.clone(), e))?;
+                // Cookie is `__cookie__:password`, password is random hex
+                let cookie = content.trim();
+                let (username, password) = match cookie.find(':') {
+                    Some(pos) => (&cookie[..pos], &cookie[pos + 1..]),
+                    None => return Err(BitcoindError::CookieInvalid(path.clone())),
+                };
+
+                let value = basic_header(username, password);
+                *header.write().unwrap() = Some(value.clone());
+                Ok(Some(value))
+            }
+        }
+    }
+}
+
+// https://docs.rs/reqwest/0.10.1/src/reqwest/async_impl/request.rs.html#183-199
+fn basic_header(username: &str, password: &str) -> HeaderValue {
+    let mut auth = b"Basic ".to_vec();
+    {
+        let mut encoder = Base64Encoder::new(&mut auth, base64::STANDARD);
+        // The unwraps here are fine because Vec::write* is infallible.
+        write!(encoder, "{}:{}", username, password).unwrap();
+    }
+
+    let mut header = HeaderValue::from_bytes(&auth)
+        .expect("Not possible build auth from provided username/password");
+    header.set_sensitive(true);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_cookie() {
+        let path = std::env::temp_dir().join(format!("telescope-cookie-{}", std::process::id()));
+        let auth = RPCAuth::cookie(path.to_str().unwrap());
+        assert!(matches!(auth.header(), Err(BitcoindError::Cookie(_, _))));
+
+        fs::write(&path, "__cookie__:abcd\n").unwrap();
+        let header = auth.header().unwrap();
+        assert_eq!(header, basic_header("__cookie__", "abcd"));
+
+        // Rotated cookie used only after reload
+        fs::write(&path, "__cookie__:0123").unwrap();
+        assert_eq!(auth.header().unwrap(), header);
+        assert_eq!(
+            auth.reload().unwrap(),
+            Some(basic_header("__cookie__", "0123"))
+        );
+        assert_eq!(auth.header().unwrap(), basic_header("__cookie__", "0123"));
+
+        fs::write(&path, "abcd").unwrap();
+        assert!(matches!(
+            auth.reload(),
+            Err(BitcoindError::CookieInvalid(_))
+        ));
+        let _ = fs::remove_file(&path);
+
+        let auth = RPCAuth::user_pass("user", None);
+        assert_eq!(auth.header().unwrap(), basic_header("user", ""));
+        assert_eq!(auth.reload().unwrap(), None);
+    }
+}
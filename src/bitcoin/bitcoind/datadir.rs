@@ -0,0 +1,371 @@
+// Read blocks directly from `blocks/blk*.dat` files in bitcoind datadir,
+// much faster than fetch every block through RPC or REST.
+//
+// Bitcoind block index (`blocks/index`) is LevelDB which is locked while node
+// is running, so we build own index: scan block headers in all files and link
+// them by previous block hash, from node tip down to first available block.
+// Since Bitcoin Core 28 files can be obfuscated with key from `blocks/xor.dat`.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::error::{BitcoindError, BitcoindResult};
+use super::raw::{double_sha256, read_hash};
+use crate::bitcoin::params::ChainParams;
+use crate::fixed_hash::H256;
+
+// Every block in file prefixed with magic and block size
+static RECORD_HEADER_SIZE: u64 = 8;
+static BLOCK_HEADER_SIZE: u64 = 80;
+
+// Blocks are read by height, so neighbour blocks are in same or few files,
+// there is no reason keep all files open (few thousands on mainnet)
+static OPEN_FILES_MAX: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct BlockFiles {
+    dir: PathBuf,
+    magic: [u8; 4],
+}
+
+// Blocks positions, ordered by height
+#[derive(Debug)]
+pub struct BlockFilesIndex {
+    dir: PathBuf,
+    xor_key: [u8; 8],
+    start_height: u32,
+    blocks: Vec<(H256, BlockPosition)>,
+    // Recently used files, last is most recent
+    files: Mutex<Vec<(u32, Arc<File>)>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockPosition {
+    file: u32,
+    offset: u64,
+    size: u32,
+}
+
+impl BlockFiles {
+    pub fn new(datadir: &str, chain: &ChainParams) -> BitcoindResult<BlockFiles> {
+        let dir = Path::new(datadir).join(chain.datadir).join("blocks");
+        fs::read_dir(&dir).map_err(BitcoindError::BlockFiles)?;
+
+        Ok(BlockFiles {
+            dir,
+            magic: chain.magic,
+        })
+    }
+
+    // Build index of blocks from node tip. Blocks which are not in files (or
+    // not linked to tip) are not included. Blocking function.
+    pub fn build_index(&self, tip_height: u32, tip_hash: H256) -> BitcoindResult<BlockFilesIndex> {
+        let xor_key = read_xor_key(&self.dir).map_err(BitcoindError::BlockFiles)?;
+
+        let mut headers = HashMap::new();
+        for (number, path) in list_files(&self.dir).map_err(BitcoindError::BlockFiles)? {
+            scan_file(&path, number, &self.magic, &xor_key, &mut headers)
+                .map_err(BitcoindError::BlockFiles)?;
+        }
+
+        let mut blocks = vec![];
+        let mut hash = tip_hash;
+        while blocks.len() <= tip_height as usize {
+            match headers.remove(&hash) {
+                Some((prev_hash, position)) => {
+                    blocks.push((hash, position));
+                    hash = prev_hash;
+                }
+                None => break,
+            }
+        }
+        blocks.reverse();
+
+        Ok(BlockFilesIndex {
+            dir: self.dir.clone(),
+            xor_key,
+            start_height: tip_height + 1 - blocks.len() as u32,
+            blocks,
+            files: Mutex::new(vec![]),
+        })
+    }
+}
+
+impl BlockFilesIndex {
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // Read serialized block with hash, `None` if block not in index.
+    // Blocking function.
+    pub fn read_block(&self, height: u32) -> BitcoindResult<Option<(H256, Vec<u8>)>> {
+        let (hash, position) = match height
+            .checked_sub(self.start_height)
+            .and_then(|index| self.blocks.get(index as usize))
+        {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        let file = self
+            .open_file(position.file)
+            .map_err(BitcoindError::BlockFiles)?;
+        let mut data = vec![0; position.size as usize];
+        read_at(&file, position.offset, &mut data, &self.xor_key)
+            .map_err(BitcoindError::BlockFiles)?;
+
+        Ok(Some((*hash, data)))
+    }
+
+    // File from cache or open new one, least recently used file is closed
+    // if there are too many open files
+    fn open_file(&self, number: u32) -> io::Result<Arc<File>> {
+        let mut files = self.files.lock().unwrap();
+        let file = match files.iter().position(|(file, _)| *file == number) {
+            Some(index) => files.remove(index).1,
+            None => Arc::new(File::open(self.dir.join(file_name(number)))?),
+        };
+
+        if files.len() == OPEN_FILES_MAX {
+            files.remove(0);
+        }
+        files.push((number, Arc::clone(&file)));
+        Ok(file)
+    }
+}
+
+fn file_name(number: u32) -> String {
+    format!("blk{:05}.dat", number)
+}
+
+// Files `blk?????.dat` sorted by number
+fn list_files(dir: &Path) -> io::Result<Vec<(u32, PathBuf)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("blk"))
+            .and_then(|name| name.strip_suffix(".dat"))
+            .filter(|number| number.len() == 5)
+            .and_then(|number| number.parse::<u32>().ok());
+        if let Some(number) = number {
+            files.push((number, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Zero key (no obfuscation) if `xor.dat` not exists
+fn read_xor_key(dir: &Path) -> io::Result<[u8; 8]> {
+    let mut key = [0; 8];
+    match File::open(dir.join("xor.dat")) {
+        Ok(mut file) => file.read_exact(&mut key)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    Ok(key)
+}
+
+// Positional read, same file can be used from few threads at same time
+fn read_at(file: &File, offset: u64, buf: &mut [u8], xor_key: &[u8; 8]) -> io::Result<()> {
+    file.read_exact_at(buf, offset)?;
+    xor(buf, offset, xor_key);
+    Ok(())
+}
+
+// Key applied by position in file
+fn xor(data: &mut [u8], offset: u64, key: &[u8; 8]) {
+    if key == &[0; 8] {
+        return;
+    }
+
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[((offset + i as u64) % 8) as usize];
+    }
+}
+
+// Add headers from file to map `hash => (prev_hash, position)`. Files are
+// preallocated by bitcoind, so we stop on zeros instead of magic.
+fn scan_file(
+    path: &Path,
+    number: u32,
+    magic: &[u8; 4],
+    xor_key: &[u8; 8],
+    headers: &mut HashMap<H256, (H256, BlockPosition)>,
+) -> io::Result<()> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut offset = 0;
+    let mut buf = [0; (RECORD_HEADER_SIZE + BLOCK_HEADER_SIZE) as usize];
+    while offset + buf.len() as u64 <= len {
+        read_at(&file, offset, &mut buf, xor_key)?;
+        if &buf[0..4] != magic {
+            break;
+        }
+
+        let size = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let block_offset = offset + RECORD_HEADER_SIZE;
+        if (size as u64) < BLOCK_HEADER_SIZE || block_offset + size as u64 > len {
+            break;
+        }
+
+        let header = &buf[RECORD_HEADER_SIZE as usize..];
+        let position = BlockPosition {
+            file: number,
+            offset: block_offset,
+            size,
+        };
+        headers.insert(double_sha256(header), (read_hash(&header[4..36]), position));
+
+        offset = block_offset + size as u64;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::bitcoin::params::CoinParams;
+
+    static GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    // Regtest datadir with 3 blocks: genesis, child and grandchild, where
+    // blocks are not ordered in files and second file have preallocated space
+    struct Fixture {
+        datadir: PathBuf,
+        blocks: Vec<(H256, Vec<u8>)>,
+    }
+
+    impl Fixture {
+        fn new(name: &str, xor_key: [u8; 8]) -> Fixture {
+            let datadir = std::env::temp_dir().join(format!(
+                "telescope-datadir-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let dir = datadir.join("regtest").join("blocks");
+            let _ = fs::remove_dir_all(&datadir);
+            fs::create_dir_all(&dir).unwrap();
+
+            let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+            let mut blocks = vec![(double_sha256(&genesis[0..80]), genesis.clone())];
+            for _ in 0..2 {
+                let mut block = genesis.clone();
+                let mut prev_hash = blocks.last().unwrap().0;
+                prev_hash.0.reverse();
+                block[4..36].copy_from_slice(prev_hash.as_bytes());
+                blocks.push((double_sha256(&block[0..80]), block));
+            }
+
+            let magic = [0xfa, 0xbf, 0xb5, 0xda];
+            let record = |block: &[u8]| {
+                let mut data = magic.to_vec();
+                data.extend_from_slice(&(block.len() as u32).to_le_bytes());
+                data.extend_from_slice(block);
+                data
+            };
+            let mut file0 = record(&blocks[0].1);
+            file0.extend(record(&blocks[2].1));
+            let mut file1 = record(&blocks[1].1);
+            file1.extend(vec![0; 100]);
+
+            for (number, mut data) in [(0, file0), (1, file1)] {
+                xor(&mut data, 0, &xor_key);
+                fs::write(dir.join(file_name(number)), data).unwrap();
+            }
+            if xor_key != [0; 8] {
+                let mut file = File::create(dir.join("xor.dat")).unwrap();
+                file.write_all(&xor_key).unwrap();
+            }
+
+            Fixture { datadir, blocks }
+        }
+
+        fn files(&self) -> BlockFiles {
+            let (_coin, chain) = CoinParams::get("bitcoin", "regtest").unwrap();
+            BlockFiles::new(self.datadir.to_str().unwrap(), chain).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.datadir);
+        }
+    }
+
+    #[test]
+    fn datadir_read_blocks() {
+        for (name, xor_key) in &[("plain", [0; 8]), ("xor", [1, 2, 3, 4, 5, 6, 7, 8])] {
+            let fixture = Fixture::new(name, *xor_key);
+            let index = fixture.files().build_index(2, fixture.blocks[2].0).unwrap();
+
+            assert_eq!(index.len(), 3);
+            for (height, block) in fixture.blocks.iter().enumerate() {
+                assert_eq!(
+                    index.read_block(height as u32).unwrap().as_ref(),
+                    Some(block)
+                );
+            }
+            assert_eq!(index.read_block(3).unwrap(), None);
+
+            // Files are opened once, most recent is last
+            let files = index.files.lock().unwrap();
+            let numbers = files.iter().map(|(number, _)| *number).collect::<Vec<_>>();
+            assert_eq!(numbers, vec![1, 0]);
+        }
+    }
+
+    #[test]
+    fn datadir_open_files_max() {
+        let fixture = Fixture::new("open", [0; 8]);
+        let index = fixture.files().build_index(2, fixture.blocks[2].0).unwrap();
+
+        // Missed files can not be opened, so cache is filled manually
+        let file =
+            Arc::new(File::open(fixture.datadir.join("regtest/blocks/blk00000.dat")).unwrap());
+        *index.files.lock().unwrap() = (2..2 + OPEN_FILES_MAX as u32)
+            .map(|number| (number, Arc::clone(&file)))
+            .collect();
+
+        index.read_block(1).unwrap();
+        let files = index.files.lock().unwrap();
+        assert_eq!(files.len(), OPEN_FILES_MAX);
+        assert_eq!(files[0].0, 3);
+        assert_eq!(files[OPEN_FILES_MAX - 1].0, 1);
+    }
+
+    #[test]
+    fn datadir_index_from_tip() {
+        let fixture = Fixture::new("tip", [0; 8]);
+        let files = fixture.files();
+
+        // Blocks after tip are not included
+        let index = files.build_index(11, fixture.blocks[1].0).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.read_block(9).unwrap(), None);
+        assert_eq!(
+            index.read_block(10).unwrap(),
+            Some(fixture.blocks[0].clone())
+        );
+        assert_eq!(
+            index.read_block(11).unwrap(),
+            Some(fixture.blocks[1].clone())
+        );
+
+        // Tip not in files
+        let index = files.build_index(5, H256::zero()).unwrap();
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.read_block(5).unwrap(), None);
+    }
+}
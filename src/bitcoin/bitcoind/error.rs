@@ -1,7 +1,10 @@
 use reqwest::Error as ReqwestError;
 use serde_json::Error as SerdeError;
+use tokio::task::JoinError;
 use url::ParseError as UrlParseError;
+use zmq::Error as ZMQError;
 
+use super::http::Timeout;
 use super::json::ResponseError;
 use crate::shutdown::ShutdownSignal;
 
@@ -9,27 +12,60 @@ quick_error! {
     #[derive(Debug)]
     pub enum BitcoindError {
         Shutdown(err: ShutdownSignal) {}
+        UnsupportedChain(coin: String, chain: String) {
+            display(r#"Chain "{}" is not supported for coin "{}""#, chain, coin)
+        }
         InvalidUrl(err: UrlParseError) {
             display("Invalid URL ({})", err)
         }
         InvalidUrlScheme(scheme: String) {
             display(r#"URL scheme "{}" is not supported"#, scheme)
         }
+        Cookie(path: std::path::PathBuf, err: std::io::Error) {
+            display("Failed to read cookie file {}: {}", path.display(), err)
+        }
+        CookieInvalid(path: std::path::PathBuf) {
+            display("Invalid cookie file {}, expected `username:password`", path.display())
+        }
+        Unauthorized {
+            display("Bitcoind RPC authorization failed (invalid username/password or cookie?)")
+        }
         Reqwest(err: ReqwestError) {
             display("{}", err)
         }
+        Timeout(timeout: Timeout) {
+            display("Bitcoind {} exceeded", timeout)
+        }
         ResponseParse(err: SerdeError) {
             display("Invalid JSON response ({})", err)
         }
+        ResponseDecode(msg: String) {
+            display("Invalid raw response ({})", msg)
+        }
+        BlockFiles(err: std::io::Error) {
+            display("Block files error: {}", err)
+        }
+        TaskJoin(err: JoinError) {
+            display("Blocking task failed: {}", err)
+        }
         NonceMismatch {
             display("Nonce mismatch")
         }
         ResultRest(code: u16, msg: String) {
             display("Bitcoind REST error (code: {}): {}", code, msg)
         }
+        ZMQ(err: ZMQError) {
+            display("ZMQ error: {}", err)
+        }
+        ZMQInvalidMessage(topic: String) {
+            display(r#"Invalid ZMQ message for topic "{}""#, topic)
+        }
         ResultRPC(err: ResponseError) {
             display("{}", err)
         }
+        BlockPruned(height: u32) {
+            display("Block at height {} not available, pruned by node (indexer is behind node prune window?)", height)
+        }
         ResultNotFound {
             display("Requested object not found")
         }
@@ -0,0 +1,153 @@
+// HTTP settings shared by RPC and REST clients. Every limit is configurable
+// from args, because big blocks on busy nodes can require much more time.
+
+use std::fmt;
+use std::time::Duration;
+
+use humantime::{format_duration, parse_duration};
+use reqwest::{header, redirect, Client, ClientBuilder};
+
+use super::error::BitcoindError;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub chaininfo_timeout: Duration,
+    // Added to `request_timeout` for block requests
+    pub block_timeout_per_mb: Duration,
+    // Idle keep-alive connections per node
+    pub pool_size: usize,
+    // Requests in flight per node, hyper do not pipeline HTTP/1.1 requests,
+    // so this is also limit of open connections
+    pub max_requests: usize,
+}
+
+// Which limit was hit, for error message
+#[derive(Debug, Clone, Copy)]
+pub enum Timeout {
+    Connect(Duration),
+    Request(Duration),
+    ChainInfo(Duration),
+    Block(Duration),
+}
+
+impl HttpConfig {
+    pub fn from_args(args: &clap::ArgMatches<'_>) -> HttpConfig {
+        let duration = |name| parse_duration(args.value_of(name).unwrap()).unwrap();
+        let number = |name| args.value_of(name).unwrap().parse().unwrap();
+        HttpConfig {
+            connect_timeout: duration("bitcoind_connect_timeout"),
+            request_timeout: duration("bitcoind_request_timeout"),
+            chaininfo_timeout: duration("bitcoind_chaininfo_timeout"),
+            block_timeout_per_mb: duration("bitcoind_block_timeout_per_mb"),
+            pool_size: number("bitcoind_pool_size"),
+            max_requests: number("bitcoind_max_requests"),
+        }
+    }
+
+    pub fn build_client(&self) -> Result<Client, BitcoindError> {
+        let mut headers = header::HeaderMap::with_capacity(1);
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("applicaiton/json"),
+        );
+
+        ClientBuilder::new()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .pool_max_idle_per_host(self.pool_size)
+            .default_headers(headers)
+            .no_gzip()
+            .redirect(redirect::Policy::none())
+            .build()
+            .map_err(BitcoindError::Reqwest)
+    }
+
+    pub fn request(&self) -> Timeout {
+        Timeout::Request(self.request_timeout)
+    }
+
+    pub fn chaininfo(&self) -> Timeout {
+        Timeout::ChainInfo(self.chaininfo_timeout)
+    }
+
+    // Timeout for block with expected size in bytes. Expected size is size of
+    // last received block (on start: last stored block), neighbour blocks
+    // usually have similar size. Timeout multiplied by attempt (starts from
+    // 1), because block can be bigger than expected or node busy
+    pub fn block(&self, size: u32, attempt: u32) -> Timeout {
+        let per_byte = self.block_timeout_per_mb / 1_000_000;
+        Timeout::Block((self.request_timeout + per_byte * size) * attempt)
+    }
+
+    // Timeouts are converted to error with limit, other errors as is
+    pub fn map_error(&self, error: reqwest::Error, timeout: Timeout) -> BitcoindError {
+        if !error.is_timeout() {
+            BitcoindError::Reqwest(error)
+        } else if error.is_connect() {
+            BitcoindError::Timeout(Timeout::Connect(self.connect_timeout))
+        } else {
+            BitcoindError::Timeout(timeout)
+        }
+    }
+}
+
+impl Timeout {
+    pub fn duration(&self) -> Duration {
+        match *self {
+            Timeout::Connect(duration)
+            | Timeout::Request(duration)
+            | Timeout::ChainInfo(duration)
+            | Timeout::Block(duration) => duration,
+        }
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, arg) = match self {
+            Timeout::Connect(_) => ("connect", "--bitcoind-connect-timeout"),
+            Timeout::Request(_) => ("request", "--bitcoind-request-timeout"),
+            Timeout::ChainInfo(_) => ("chaininfo", "--bitcoind-chaininfo-timeout"),
+            Timeout::Block(_) => ("block request", "--bitcoind-block-timeout-per-mb"),
+        };
+        write!(
+            f,
+            "{} timeout ({}, see {})",
+            name,
+            format_duration(self.duration()),
+            arg
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_block_timeout() {
+        let config = HttpConfig {
+            connect_timeout: Duration::from_millis(250),
+            request_timeout: Duration::from_secs(30),
+            chaininfo_timeout: Duration::from_millis(250),
+            block_timeout_per_mb: Duration::from_secs(10),
+            pool_size: 16,
+            max_requests: 16,
+        };
+        assert_eq!(config.block(0, 1).duration(), Duration::from_secs(30));
+        assert_eq!(
+            config.block(1_000_000, 1).duration(),
+            Duration::from_secs(40)
+        );
+        assert_eq!(
+            config.block(4_000_000, 1).duration(),
+            Duration::from_secs(70)
+        );
+        assert_eq!(
+            config.block(1_000_000, 3).duration(),
+            Duration::from_secs(120)
+        );
+    }
+}
@@ -2,6 +2,7 @@ use std::fmt;
 
 use serde::{de, Deserialize, Deserializer, Serialize};
 
+use crate::bitcoin::amount::Amount;
 use crate::fixed_hash::H256;
 
 #[derive(Debug, Serialize)]
@@ -46,6 +47,11 @@ pub struct BlockchainInfo {
     pub blocks: u32,
     #[serde(deserialize_with = "H256::deserialize_hex")]
     pub bestblockhash: H256,
+    #[serde(default)]
+    pub pruned: bool,
+    // Lowest height of stored block, only if `pruned` is true
+    #[serde(default)]
+    pub pruneheight: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -73,6 +79,8 @@ pub struct Block {
 
 #[derive(Debug, Deserialize)]
 pub struct Transaction {
+    #[serde(deserialize_with = "H256::deserialize_hex")]
+    pub txid: H256,
     #[serde(deserialize_with = "H256::deserialize_hex")]
     pub hash: H256,
     #[serde(deserialize_with = "hex::deserialize")]
@@ -104,6 +112,33 @@ impl<'de> Deserialize<'de> for TransactionInput {
             where
                 V: de::MapAccess<'de>,
             {
+                // Keys and values are owned, because blocks are parsed
+                // from reader, where borrowed strings are not available
+                #[derive(Deserialize)]
+                #[serde(field_identifier)]
+                enum Field {
+                    #[serde(rename = "coinbase")]
+                    Coinbase,
+                    #[serde(rename = "txid")]
+                    Txid,
+                    #[serde(rename = "vout")]
+                    Vout,
+                    #[serde(rename = "sequence")]
+                    Sequence,
+                    #[serde(rename = "scriptSig")]
+                    ScriptSig,
+                    #[serde(rename = "txinwitness")]
+                    TxInWitness,
+                    #[serde(rename = "ismweb")]
+                    IsMweb,
+                }
+
+                #[derive(Deserialize)]
+                struct Hex(#[serde(deserialize_with = "hex::deserialize")] Vec<u8>);
+
+                #[derive(Deserialize)]
+                struct Hash(#[serde(deserialize_with = "H256::deserialize_hex")] H256);
+
                 let mut coinbase: Option<Vec<u8>> = None;
                 let mut txid: Option<Option<H256>> = None;
                 let mut vout: Option<u32> = None;
@@ -118,40 +153,32 @@ impl<'de> Deserialize<'de> for TransactionInput {
 
                 while let Some(key) = visitor.next_key()? {
                     match key {
-                        "coinbase" => {
+                        Field::Coinbase => {
                             check_duplicate!(coinbase, "coinbase");
-                            let value = visitor.next_value()?;
-                            coinbase = Some(hex::decode(value).map_err(|_| {
-                                de::Error::invalid_value(de::Unexpected::Str(value), &self)
-                            })?);
+                            coinbase = Some(visitor.next_value::<Hex>()?.0);
                         }
-                        "txid" => {
+                        Field::Txid => {
                             check_duplicate!(txid, "txid");
-                            let value = visitor.next_value()?;
-                            let mut hash = H256::zero();
-                            hex::decode_to_slice(value, &mut hash.0 as &mut [u8]).map_err(
-                                |_| de::Error::invalid_value(de::Unexpected::Str(value), &self),
-                            )?;
-                            txid = Some(Some(hash));
+                            txid = Some(Some(visitor.next_value::<Hash>()?.0));
                         }
-                        "vout" => {
+                        Field::Vout => {
                             check_duplicate!(vout, "vout");
                             vout = Some(visitor.next_value::<u32>()?);
                         }
-                        "sequence" => {
+                        Field::Sequence => {
                             visitor.next_value::<u32>()?;
                         }
-                        "scriptSig" => {
-                            #[derive(Deserialize)]
-                            struct ScriptSig {};
-
-                            visitor.next_value::<ScriptSig>()?;
-                        }
-                        "txinwitness" => {
-                            visitor.next_value::<Vec<&str>>()?;
+                        Field::ScriptSig | Field::TxInWitness => {
+                            visitor.next_value::<de::IgnoredAny>()?;
                         }
-                        _ => {
-                            return Err(de::Error::unknown_field(key, &[]));
+                        // Litecoin, only canonical inputs expected
+                        Field::IsMweb => {
+                            if visitor.next_value::<bool>()? {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Bool(true),
+                                    &self,
+                                ));
+                            }
                         }
                     }
                 }
@@ -190,45 +217,17 @@ impl<'de> Deserialize<'de> for TransactionInput {
 
 #[derive(Debug, Deserialize)]
 pub struct TransactionOutput {
-    // Require `serde_json` with feature `arbitrary_precision`
-    #[serde(deserialize_with = "de_vout_value")]
-    pub value: String,
+    #[serde(deserialize_with = "Amount::deserialize_btc")]
+    pub value: Amount,
     #[serde(rename = "scriptPubKey")]
     pub script: TransactionOutputScript,
 }
 
-fn de_vout_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
-    struct Visitor;
-
-    impl<'de> de::Visitor<'de> for Visitor {
-        type Value = String;
-
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a JSON number")
-        }
-
-        fn visit_map<V>(self, mut visitor: V) -> Result<String, V::Error>
-        where
-            V: de::MapAccess<'de>,
-        {
-            let value = visitor.next_key::<String>()?;
-            if value.is_none() {
-                return Err(de::Error::invalid_type(de::Unexpected::Map, &self));
-            }
-            visitor.next_value()
-        }
-    }
-
-    deserializer.deserialize_any(Visitor)
-}
-
 #[derive(Debug, Deserialize)]
 pub struct TransactionOutputScript {
-    pub addresses: Option<Vec<String>>,
+    #[serde(deserialize_with = "hex::deserialize")]
+    pub hex: Vec<u8>,
+    // Derived from `hex`, not all bitcoind versions return `addresses`
+    #[serde(skip)]
+    pub addresses: Vec<String>,
 }
-
-// TODO: return parsed value in satoshi
-// impl TransactionOutput {
-//     pub fn get_value_satoshi(&self, coin: &str) {
-//     }
-// }
@@ -1,106 +1,131 @@
-use std::io::Write;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use base64::write::EncoderWriter as Base64Encoder;
+use futures::future;
+use humantime::format_duration;
 use regex::Regex;
 use semver::{Version, VersionReq};
-use url::Url;
+use tokio::time::delay_for;
 
+use self::datadir::{BlockFiles, BlockFilesIndex};
 use self::error::{BitcoindError, BitcoindResult};
-use self::json::{Block, BlockchainInfo};
-use self::rest::RESTClient;
-use self::rpc::RPCClient;
-use crate::logger::info;
+use self::http::HttpConfig;
+use self::json::{Block, BlockchainInfo, Transaction};
+use self::node::Node;
+use self::retry::{is_retryable, RETRY_ATTEMPTS};
+use self::zmq::{ZMQClient, ZMQSubscription};
+use super::params::{ChainParams, CoinParams};
+use crate::fixed_hash::H256;
+use crate::logger::{error, info};
 use crate::shutdown::Shutdown;
 
+pub use self::raw::Format;
+
+mod auth;
+mod datadir;
 pub mod error;
+mod http;
 pub mod json;
+mod node;
+mod raw;
 mod rest;
+mod retry;
 mod rpc;
-
-static EXPECTED_BITCOIND_VERSION: &[(&str, &str)] = &[("bitcoin", ">= 0.19.0")];
-
-static EXPECTED_BITCOIND_USERAGENT: &[(&str, &str)] = &[("bitcoin", "Satoshi")];
+mod stream;
+pub mod zmq;
 
 #[derive(Debug)]
 pub struct Bitcoind {
-    coin: String,
-    chain: String,
+    coin: &'static CoinParams,
+    chain: &'static ChainParams,
+
+    raw_format: bool,
+    http: HttpConfig,
+    // Expected size of next block for block request timeout: size of last
+    // fetched block, on start size of stored best block
+    block_size: AtomicU32,
 
-    rest: Option<RESTClient>,
-    rpc: RPCClient,
+    // Requests are spread across healthy nodes by round-robin
+    nodes: Vec<Node>,
+    next_node: AtomicUsize,
+    zmq: Option<ZMQClient>,
+    files: Option<BlockFiles>,
+
+    // Stop retries on shutdown
+    shutdown: Arc<Shutdown>,
 }
 
 impl Bitcoind {
-    pub fn from_args(args: &clap::ArgMatches<'_>) -> BitcoindResult<Bitcoind> {
+    pub fn from_args(
+        shutdown: Arc<Shutdown>,
+        args: &clap::ArgMatches<'_>,
+    ) -> BitcoindResult<Bitcoind> {
         // args
-        let coin = args.value_of("coin").unwrap().to_owned();
-        let chain = args.value_of("chain").unwrap().to_owned();
-        let url = args.value_of("bitcoind").unwrap();
-
-        // Parse URL
-        let (url, auth) = Bitcoind::parse_url(url)?;
+        let coin = args.value_of("coin").unwrap();
+        let chain = args.value_of("chain").unwrap();
+        let (coin, chain) = CoinParams::get(coin, chain)
+            .ok_or_else(|| BitcoindError::UnsupportedChain(coin.to_owned(), chain.to_owned()))?;
+        let urls = args.values_of("bitcoind").unwrap();
+        let cookie = args.value_of("bitcoind_cookie");
+        let http = HttpConfig::from_args(args);
+        let raw_format = args.value_of("bitcoind_format").unwrap() == "raw" || !coin.json_blocks;
+        let zmq = args
+            .value_of("bitcoind_zmq")
+            .map(|url| ZMQClient::new(url.to_owned()));
+        let files = args
+            .value_of("bitcoind_datadir")
+            .map(|datadir| BlockFiles::new(datadir, chain))
+            .transpose()?;
 
-        // We use REST client only for some coins
-        let rest = match coin.as_str() {
-            "bitcoin" => None,
-            _ => Some(RESTClient::new(url.clone())?),
-        };
+        let nodes = urls
+            .map(|url| Node::new(url, cookie, http, coin.rest_blocks))
+            .collect::<BitcoindResult<Vec<Node>>>()?;
 
         // Instance
         Ok(Bitcoind {
             coin,
             chain,
-            rest,
-            rpc: RPCClient::new(url, auth)?,
+            raw_format,
+            http,
+            block_size: AtomicU32::new(0),
+            nodes,
+            next_node: AtomicUsize::new(0),
+            zmq,
+            files,
+            shutdown,
         })
     }
 
-    // Prase given URL with username/password
-    fn parse_url(url: &str) -> BitcoindResult<(Url, Vec<u8>)> {
-        let mut parsed = Url::parse(url).map_err(BitcoindError::InvalidUrl)?;
-        match parsed.scheme() {
-            "http" | "https" => {}
-            scheme => return Err(BitcoindError::InvalidUrlScheme(scheme.to_owned())),
-        }
-
-        // https://docs.rs/reqwest/0.10.1/src/reqwest/async_impl/request.rs.html#183-199
-        let mut auth = b"Basic ".to_vec();
-        {
-            let mut encoder = Base64Encoder::new(&mut auth, base64::STANDARD);
-            // The unwraps here are fine because Vec::write* is infallible.
-            write!(encoder, "{}:", parsed.username()).unwrap();
-            if let Some(password) = parsed.password() {
-                write!(encoder, "{}", password).unwrap();
-            }
-        }
-
-        // Return Err only if `.cannot_be_a_base` is true
-        // Since we already verified that scheme is http/https, unwrap is safe
-        parsed.set_username("").unwrap();
-        parsed.set_password(None).unwrap();
-
-        Ok((parsed, auth))
+    // Every node validated separately, then nodes compared with each other
+    pub async fn validate(&self) -> BitcoindResult<()> {
+        let nodes = self.nodes.iter().map(|node| self.validate_node(node));
+        future::try_join_all(nodes).await?;
+        self.validate_nodes_same_chain().await
     }
 
-    pub async fn validate(&self, shutdown: &Arc<Shutdown>) -> BitcoindResult<()> {
-        self.validate_client_initialized(shutdown).await?;
+    async fn validate_node(&self, node: &Node) -> BitcoindResult<()> {
+        self.validate_client_initialized(node).await?;
+        // Version first, old node can fail on other checks with less clear error
+        self.validate_version(node).await?;
         tokio::try_join!(
-            self.validate_chain(),
-            self.validate_version(),
-            self.validate_clients_to_same_node(),
+            self.validate_chain(node),
+            self.validate_genesis(node),
+            self.validate_clients_to_same_node(node),
+            self.validate_pruned(node),
         )?;
         Ok(())
     }
 
-    async fn validate_client_initialized(&self, shutdown: &Arc<Shutdown>) -> BitcoindResult<()> {
+    async fn validate_client_initialized(&self, node: &Node) -> BitcoindResult<()> {
         let mut ts = SystemTime::now();
         let mut last_message = String::new();
 
         loop {
             tokio::select! {
-                info = self.rpc.get_blockchain_info() => {
+                info = node.rpc.get_blockchain_info() => {
                     match info {
                         Ok(_) => return Ok(()),
                         Err(BitcoindError::ResultRPC(error)) => {
@@ -114,7 +139,7 @@ impl Bitcoind {
                             if elapsed > Duration::from_secs(3) || last_message != error.message {
                                 ts = SystemTime::now();
                                 last_message = error.message;
-                                info!("Waiting coin client: {}", &last_message);
+                                info!("Waiting coin client {}: {}", node.url, &last_message);
                             }
 
                             let sleep_duration = Duration::from_millis(10);
@@ -123,29 +148,45 @@ impl Bitcoind {
                         Err(e) => return Err(e),
                     }
                 }
-                e = shutdown.wait() => return Err(BitcoindError::Shutdown(e)),
+                e = self.shutdown.wait() => return Err(BitcoindError::Shutdown(e)),
             }
         }
     }
 
-    async fn validate_chain(&self) -> BitcoindResult<()> {
-        let info = self.rpc.get_blockchain_info().await?;
-        if info.chain != self.chain {
+    // Chain names in args are same as in `getblockchaininfo`
+    async fn validate_chain(&self, node: &Node) -> BitcoindResult<()> {
+        let info = node.rpc.get_blockchain_info().await?;
+        if info.chain != self.chain.name {
             Err(BitcoindError::ClientInvalidX(
                 "chain".to_owned(),
                 info.chain,
-                self.chain.to_owned(),
+                self.chain.name.to_owned(),
             ))
         } else {
             Ok(())
         }
     }
 
-    async fn validate_version(&self) -> BitcoindResult<()> {
-        let info = self.rpc.get_network_info().await?;
+    async fn validate_genesis(&self, node: &Node) -> BitcoindResult<()> {
+        let hash = node.rpc.get_block_hash(0).await?;
+        let hash = hash.map(hex::encode).unwrap_or_default();
+        if hash != self.chain.genesis_hash {
+            Err(BitcoindError::ClientInvalidX(
+                "genesis block".to_owned(),
+                hash,
+                self.chain.genesis_hash.to_owned(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn validate_version(&self, node: &Node) -> BitcoindResult<()> {
+        let info = node.rpc.get_network_info().await?;
 
         // Split useragent and version from strings like: "/Satoshi:0.19.0.1/"
-        let re_split = Regex::new(r#"^/([a-zA-Z ]+):([0-9.]+)/$"#).unwrap();
+        // or with comment: "/Bitcoin Cash Node:26.1.0(EB32.0)/"
+        let re_split = Regex::new(r#"^/([a-zA-Z ]+):([0-9.]+)(\([^)]*\))?/$"#).unwrap();
         let (useragent, mut version) = match re_split.captures(&info.subversion) {
             Some(cap) => (cap.get(1).unwrap().as_str(), cap.get(2).unwrap().as_str()),
             None => {
@@ -157,52 +198,44 @@ impl Bitcoind {
         };
 
         // Validate useragent
-        for (coin, value) in EXPECTED_BITCOIND_USERAGENT {
-            if coin == &self.coin {
-                if value != &useragent {
-                    return Err(BitcoindError::ClientInvalidX(
-                        "useragent".to_owned(),
-                        useragent.to_owned(),
-                        value.to_owned().to_owned(),
-                    ));
-                }
-
-                break;
-            }
+        if useragent != self.coin.useragent {
+            return Err(BitcoindError::ClientInvalidX(
+                "useragent".to_owned(),
+                useragent.to_owned(),
+                self.coin.useragent.to_owned(),
+            ));
         }
 
         // Remove extra digits in version and validate it
         while version.matches('.').count() > 2 {
             version = &version[0..version.rfind('.').unwrap()];
         }
-        for (coin, value) in EXPECTED_BITCOIND_VERSION {
-            if coin == &self.coin {
-                let actual = match Version::parse(version) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        return Err(BitcoindError::ClientInvalidVersionX(
-                            "version".to_owned(),
-                            version.to_owned(),
-                        ))
-                    }
-                };
-                let required = VersionReq::parse(value).unwrap();
-                if !required.matches(&actual) {
-                    return Err(BitcoindError::ClientInvalidX(
-                        "version".to_owned(),
-                        version.to_owned(),
-                        value.to_owned().to_owned(),
-                    ));
-                }
+        let actual = match Version::parse(version) {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(BitcoindError::ClientInvalidVersionX(
+                    "version".to_owned(),
+                    version.to_owned(),
+                ))
+            }
+        };
+        for requirement in self.coin.version_requirements(self.chain) {
+            let required = VersionReq::parse(requirement).unwrap();
+            if !required.matches(&actual) {
+                return Err(BitcoindError::ClientInvalidX(
+                    "version".to_owned(),
+                    version.to_owned(),
+                    requirement.to_owned(),
+                ));
             }
         }
 
         Ok(())
     }
 
-    async fn validate_clients_to_same_node(&self) -> BitcoindResult<()> {
-        if let Some(ref rest) = self.rest {
-            let rpc_fut = self.rpc.get_blockchain_info();
+    async fn validate_clients_to_same_node(&self, node: &Node) -> BitcoindResult<()> {
+        if let Some(ref rest) = node.rest {
+            let rpc_fut = node.rpc.get_blockchain_info();
             let rest_fut = rest.get_blockchain_info();
             let (rpc, rest) = tokio::try_join!(rpc_fut, rest_fut)?;
             if rpc != rest {
@@ -213,17 +246,796 @@ impl Bitcoind {
         Ok(())
     }
 
+    // Pruned node is fine for follow, but initial sync require all blocks,
+    // this is checked on sync start with `get_prune_height`
+    async fn validate_pruned(&self, node: &Node) -> BitcoindResult<()> {
+        let info = node.rpc.get_blockchain_info().await?;
+        if let Some(height) = info.pruneheight.filter(|_| info.pruned) {
+            info!(
+                "Node {} is pruned, blocks available from height {}",
+                node.url, height
+            );
+        }
+        Ok(())
+    }
+
+    // Nodes can have different tips while blocks propagate, but block at
+    // smallest tip height should be same on all nodes
+    async fn validate_nodes_same_chain(&self) -> BitcoindResult<()> {
+        if self.nodes.len() < 2 {
+            return Ok(());
+        }
+
+        let infos = self.nodes.iter().map(|node| node.rpc.get_blockchain_info());
+        let infos = future::try_join_all(infos).await?;
+        let height = infos.iter().map(|info| info.blocks).min().unwrap();
+
+        let hashes = self
+            .nodes
+            .iter()
+            .map(|node| node.rpc.get_block_hash(height));
+        let hashes = future::try_join_all(hashes).await?;
+        if hashes.iter().any(|hash| *hash != hashes[0]) {
+            return Err(BitcoindError::ClientMismatch);
+        }
+
+        Ok(())
+    }
+
+    // Nodes for next request: healthy nodes from next by round-robin, then
+    // failed nodes, in case if all nodes are failed. With `height` only nodes
+    // which have block at this height, if there is at least one such node
+    // (tips are not known before first status update).
+    fn nodes_order(&self, height: Option<u32>) -> Vec<&Node> {
+        let start = self.next_node.fetch_add(1, Ordering::Relaxed);
+        let mut nodes = (0..self.nodes.len())
+            .map(|i| &self.nodes[(start + i) % self.nodes.len()])
+            .collect::<Vec<&Node>>();
+        if let Some(height) = height {
+            if nodes.iter().any(|node| node.has_block(height)) {
+                nodes.retain(|node| node.has_block(height));
+            }
+        }
+
+        let (healthy, failed): (Vec<&Node>, Vec<&Node>) =
+            nodes.into_iter().partition(|node| node.is_healthy());
+        healthy.into_iter().chain(failed).collect()
+    }
+
+    async fn request<'a, T, F, Fut>(&'a self, f: F) -> BitcoindResult<T>
+    where
+        F: Fn(&'a Node) -> Fut,
+        Fut: Future<Output = BitcoindResult<T>>,
+    {
+        self.request_with(|| self.nodes_order(None), f).await
+    }
+
+    // Request which depends from node tip, node behind return nothing
+    async fn request_at<'a, T, F, Fut>(&'a self, height: u32, f: F) -> BitcoindResult<T>
+    where
+        F: Fn(&'a Node) -> Fut,
+        Fut: Future<Output = BitcoindResult<T>>,
+    {
+        self.request_with(|| self.nodes_order(Some(height)), f)
+            .await
+    }
+
+    // Run request on nodes with failover, if all nodes failed request repeated
+    // with backoff
+    async fn request_with<'a, T, N, F, Fut>(&'a self, nodes: N, f: F) -> BitcoindResult<T>
+    where
+        N: Fn() -> Vec<&'a Node>,
+        F: Fn(&'a Node) -> Fut,
+        Fut: Future<Output = BitcoindResult<T>>,
+    {
+        let f = &f;
+        self.retry(|| self.request_nodes(nodes(), f)).await
+    }
+
+    // Repeat on retryable errors, until attempts are exhausted or shutdown
+    async fn retry<T, F, Fut>(&self, f: F) -> BitcoindResult<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = BitcoindResult<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if is_retryable(&e) && attempt < RETRY_ATTEMPTS => {
+                    let delay = retry::get_delay(attempt);
+                    error!(
+                        "Bitcoind request failed (attempt {} of {}), retry in {}: {}",
+                        attempt,
+                        RETRY_ATTEMPTS,
+                        format_duration(delay),
+                        e
+                    );
+                    tokio::select! {
+                        _ = delay_for(delay) => {},
+                        e = self.shutdown.wait() => return Err(BitcoindError::Shutdown(e)),
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Run request on first node, other nodes are used on connection errors
+    async fn request_nodes<'a, T, F, Fut>(
+        &'a self,
+        nodes: Vec<&'a Node>,
+        f: &F,
+    ) -> BitcoindResult<T>
+    where
+        F: Fn(&'a Node) -> Fut,
+        Fut: Future<Output = BitcoindResult<T>>,
+    {
+        let mut last_error = None;
+        let count = nodes.len();
+        for node in nodes {
+            match f(node).await {
+                Err(e) if is_retryable(&e) => {
+                    if node.is_healthy() && count > 1 {
+                        error!("Node {} failed, switch to other node: {}", node.url, e);
+                    }
+                    node.set_failed(true);
+                    last_error = Some(e);
+                }
+                result => {
+                    node.set_failed(false);
+                    return result;
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    pub fn set_block_size(&self, size: u32) {
+        self.block_size.store(size, Ordering::Relaxed);
+    }
+
+    // Subscribe to notifications, if ZMQ endpoint specified
+    pub fn subscribe_zmq(&self) -> BitcoindResult<Option<ZMQSubscription>> {
+        self.zmq.as_ref().map(|zmq| zmq.subscribe()).transpose()
+    }
+
+    // Chain info from node with best tip. Tips of other nodes are compared
+    // with best node chain on every call (status update), so blocks are
+    // requested only from nodes which have them.
     pub async fn get_blockchain_info(&self) -> BitcoindResult<BlockchainInfo> {
-        self.rpc.get_blockchain_info().await
+        self.retry(|| self.update_nodes_tips()).await
+    }
+
+    // Failed node (on any error) is marked as failed and off-tip, error is
+    // returned only if no node responded
+    async fn update_nodes_tips(&self) -> BitcoindResult<BlockchainInfo> {
+        let infos = self.nodes.iter().map(|node| node.rpc.get_blockchain_info());
+        let infos = future::join_all(infos).await;
+
+        let mut responded = vec![];
+        let mut last_error = None;
+        for (node, result) in self.nodes.iter().zip(infos) {
+            match result {
+                Ok(info) => {
+                    node.set_failed(false);
+                    responded.push((node, info));
+                }
+                Err(e) => {
+                    if node.is_healthy() && self.nodes.len() > 1 {
+                        error!("Node {} failed: {}", node.url, e);
+                    }
+                    node.set_failed(true);
+                    node.set_tip_height(None);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        let best = match (0..responded.len()).max_by_key(|i| responded[*i].1.blocks) {
+            Some(best) => best,
+            None => return Err(last_error.unwrap()),
+        };
+        let (best_node, best_info) = &responded[best];
+        for (node, info) in responded.iter() {
+            let hash = if info.blocks == best_info.blocks {
+                Some(best_info.bestblockhash)
+            } else {
+                match best_node.rpc.get_block_hash(info.blocks).await {
+                    Ok(hash) => hash,
+                    // Tip can not be checked, so node is off-tip until next update
+                    Err(e) => {
+                        error!("Node {} failed on tip check: {}", best_node.url, e);
+                        best_node.set_failed(true);
+                        None
+                    }
+                }
+            };
+
+            let tip = Some(info.blocks).filter(|_| hash == Some(info.bestblockhash));
+            if node.set_tip_height(tip).is_some() && tip.is_none() {
+                error!(
+                    "Node {} tip {} ({}) is not in best chain, blocks not requested from it",
+                    node.url, info.blocks, info.bestblockhash
+                );
+            }
+        }
+
+        Ok(responded.swap_remove(best).1)
+    }
+
+    // Node with block at `height` for sequence of requests, where answers
+    // from nodes on different tips can not be mixed (reorg search)
+    pub fn pin_node(&self, height: u32) -> PinnedNode<'_> {
+        PinnedNode {
+            bitcoind: self,
+            node: self.nodes_order(Some(height))[0],
+        }
+    }
+
+    // Lowest height of block available on all nodes, `None` if nodes are not
+    // pruned. Failed nodes are skipped.
+    pub async fn get_prune_height(&self) -> BitcoindResult<Option<u32>> {
+        let mut prune_height = None;
+        let mut last_error = None;
+        let mut responded = false;
+        for node in self.nodes.iter() {
+            match node.rpc.get_blockchain_info().await {
+                Ok(info) => {
+                    if let Some(height) = info.pruneheight.filter(|_| info.pruned) {
+                        prune_height = std::cmp::max(prune_height, Some(height));
+                    }
+                    responded = true;
+                }
+                Err(e) if is_retryable(&e) => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        match last_error {
+            Some(e) if !responded => Err(e),
+            _ => Ok(prune_height),
+        }
+    }
+
+    // Node with first height is used, hashes after its tip are `None`
+    pub async fn get_block_hashes(&self, heights: Range<u32>) -> BitcoindResult<Vec<Option<H256>>> {
+        self.request_at(heights.start, |node| {
+            node.rpc.get_block_hashes(heights.clone())
+        })
+        .await
+    }
+
+    pub async fn get_raw_mempool(&self) -> BitcoindResult<Vec<H256>> {
+        self.request(|node| node.rpc.get_raw_mempool()).await
+    }
+
+    // Transactions in one batch request, `None` if transaction is not in
+    // mempool anymore or has nothing to index
+    pub async fn get_raw_transactions(
+        &self,
+        txids: &[H256],
+    ) -> BitcoindResult<Vec<Option<Transaction>>> {
+        self.request(|node| self.get_raw_transactions_node(node, txids))
+            .await
+    }
+
+    async fn get_raw_transactions_node(
+        &self,
+        node: &Node,
+        txids: &[H256],
+    ) -> BitcoindResult<Vec<Option<Transaction>>> {
+        if self.raw_format {
+            let txs = node.rpc.get_raw_transactions_bytes(txids).await?;
+            return txs
+                .into_iter()
+                .zip(txids)
+                .map(|(data, txid)| match data {
+                    Some(data) => self.decode_mempool_transaction(&data, *txid),
+                    None => Ok(None),
+                })
+                .collect();
+        }
+
+        let mut txs = node.rpc.get_raw_transactions(txids).await?;
+        for tx in txs.iter_mut().flatten() {
+            self.set_addresses(std::slice::from_mut(tx));
+        }
+        Ok(txs)
+    }
+
+    fn decode_mempool_transaction(
+        &self,
+        data: &[u8],
+        txid: H256,
+    ) -> BitcoindResult<Option<Transaction>> {
+        let tx = self.decode_transaction(data)?;
+        // MWEB only transaction (Litecoin), nothing to index
+        // and txid is not hash of canonical part
+        if tx.inputs.is_empty() && tx.outputs.is_empty() {
+            return Ok(None);
+        }
+        if tx.txid != txid {
+            return Err(BitcoindError::ResultMismatch);
+        }
+        Ok(Some(tx))
     }
 
     pub async fn get_block_by_height(&self, height: u32) -> BitcoindResult<Option<Block>> {
-        match self.rpc.get_block_hash(height).await? {
-            Some(hash) => match self.rest {
-                Some(ref rest) => rest.get_block_by_hash(hash).await,
-                None => self.rpc.get_block_by_hash(hash).await,
-            },
+        let attempt = AtomicU32::new(1);
+        let result = self
+            .request_at(height, |node| {
+                let attempt = attempt.fetch_add(1, Ordering::Relaxed);
+                self.get_block_by_height_node(node, height, attempt)
+            })
+            .await;
+        match result {
+            Err(ref e) if is_pruned_error(e) => Err(BitcoindError::BlockPruned(height)),
+            result => result,
+        }
+    }
+
+    // Hash and block from same node, other node can be on other chain
+    async fn get_block_by_height_node(
+        &self,
+        node: &Node,
+        height: u32,
+        attempt: u32,
+    ) -> BitcoindResult<Option<Block>> {
+        match node.rpc.get_block_hash(height).await? {
+            Some(hash) => {
+                self.get_block_by_hash_node(node, hash, height, attempt)
+                    .await
+            }
             None => Ok(None),
         }
     }
+
+    // Block with known hash, height required for raw format
+    pub async fn get_block_by_hash(
+        &self,
+        hash: H256,
+        height: u32,
+    ) -> BitcoindResult<Option<Block>> {
+        let attempt = AtomicU32::new(1);
+        let result = self
+            .request_at(height, |node| {
+                let attempt = attempt.fetch_add(1, Ordering::Relaxed);
+                self.get_block_by_hash_node(node, hash, height, attempt)
+            })
+            .await;
+        match result {
+            Err(ref e) if is_pruned_error(e) => Err(BitcoindError::BlockPruned(height)),
+            result => result,
+        }
+    }
+
+    async fn get_block_by_hash_node(
+        &self,
+        node: &Node,
+        hash: H256,
+        height: u32,
+        attempt: u32,
+    ) -> BitcoindResult<Option<Block>> {
+        // Neighbour blocks usually have similar size
+        let size = self.block_size.load(Ordering::Relaxed);
+        let timeout = self.http.block(size, attempt);
+
+        let mut block = if !self.raw_format {
+            match node.rest {
+                Some(ref rest) => rest.get_block_by_hash(hash, timeout).await?,
+                None => node.rpc.get_block_by_hash(hash, timeout).await?,
+            }
+        } else {
+            let data = match node.rest {
+                Some(ref rest) => rest.get_raw_block_by_hash(hash, timeout).await?,
+                None => node.rpc.get_raw_block_by_hash(hash, timeout).await?,
+            };
+            match data {
+                Some(data) => {
+                    let block = self.decode_block(&data, height)?;
+                    // Check that received block match to requested
+                    if block.hash != hash {
+                        return Err(BitcoindError::ResultMismatch);
+                    }
+                    Some(block)
+                }
+                None => None,
+            }
+        };
+
+        if let Some(ref mut block) = block {
+            self.block_size.store(block.size, Ordering::Relaxed);
+            if !self.raw_format {
+                self.set_addresses(&mut block.transactions);
+            }
+        }
+        Ok(block)
+    }
+
+    // Index blocks in `blk*.dat` files up to node tip, if datadir specified.
+    // Datadir is expected to be datadir of first node.
+    pub async fn index_block_files(&self) -> BitcoindResult<Option<Arc<BlockFilesIndex>>> {
+        let files = match self.files {
+            Some(ref files) => files.clone(),
+            None => return Ok(None),
+        };
+
+        let ts = SystemTime::now();
+        let info = self.nodes[0].rpc.get_blockchain_info().await?;
+        let index =
+            tokio::task::spawn_blocking(move || files.build_index(info.blocks, info.bestblockhash))
+                .await
+                .map_err(BitcoindError::TaskJoin)??;
+
+        let elapsed = format_duration(ts.elapsed().unwrap());
+        info!(
+            "Indexed {} blocks in block files in {}",
+            index.len(),
+            elapsed
+        );
+
+        Ok(Some(Arc::new(index)))
+    }
+
+    // Read block from files, fallback to `get_block_by_height` if block not in index
+    pub async fn get_block_from_files(
+        &self,
+        index: &Arc<BlockFilesIndex>,
+        height: u32,
+    ) -> BitcoindResult<Option<Block>> {
+        let index = Arc::clone(index);
+        let (coin, chain) = (self.coin, self.chain);
+        // Both read and decode are CPU/IO heavy, so not on async worker
+        let block = tokio::task::spawn_blocking(move || match index.read_block(height)? {
+            Some((hash, data)) => {
+                let block = decode_block(coin, chain, &data, height)?;
+                if block.hash != hash {
+                    return Err(BitcoindError::ResultMismatch);
+                }
+                Ok(Some(block))
+            }
+            None => Ok(None),
+        })
+        .await
+        .map_err(BitcoindError::TaskJoin)??;
+
+        match block {
+            Some(block) => Ok(Some(block)),
+            None => self.get_block_by_height(height).await,
+        }
+    }
+
+    fn decode_block(&self, data: &[u8], height: u32) -> BitcoindResult<Block> {
+        decode_block(self.coin, self.chain, data, height)
+    }
+
+    fn decode_transaction(&self, data: &[u8]) -> BitcoindResult<Transaction> {
+        let mut tx = raw::decode_transaction(data, self.coin.format)?;
+        self.set_addresses(std::slice::from_mut(&mut tx));
+        Ok(tx)
+    }
+
+    fn set_addresses(&self, transactions: &mut [Transaction]) {
+        set_addresses(self.chain, transactions)
+    }
+}
+
+// Raw format do not have addresses, so they always derived on decode
+fn decode_block(
+    coin: &CoinParams,
+    chain: &ChainParams,
+    data: &[u8],
+    height: u32,
+) -> BitcoindResult<Block> {
+    let mut block = raw::decode_block(data, height, coin.format)?;
+    set_addresses(chain, &mut block.transactions);
+    Ok(block)
+}
+
+// Derive output addresses from script, same for JSON and raw format
+fn set_addresses(chain: &ChainParams, transactions: &mut [Transaction]) {
+    for tx in transactions.iter_mut() {
+        for output in tx.outputs.iter_mut() {
+            output.script.addresses = chain.address_prefixes.get_addresses(&output.script.hex);
+        }
+    }
+}
+
+// Requests to one node, other nodes are not used even on errors
+pub struct PinnedNode<'a> {
+    bitcoind: &'a Bitcoind,
+    node: &'a Node,
+}
+
+impl PinnedNode<'_> {
+    pub async fn get_block_hash(&self, height: u32) -> BitcoindResult<Option<H256>> {
+        let node = self.node;
+        self.bitcoind
+            .request_with(|| vec![node], |node| node.rpc.get_block_hash(height))
+            .await
+    }
+}
+
+// Pruned block errors: RPC "Block not available (pruned data)" (code -1),
+// REST "{hash} not available (pruned data)" (404)
+fn is_pruned_error(error: &BitcoindError) -> bool {
+    match error {
+        BitcoindError::ResultRPC(error) => {
+            error.code == -1 && error.message.contains("(pruned data)")
+        }
+        BitcoindError::ResultRest(404, msg) => msg.contains("(pruned data)"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::net::TcpListener;
+
+    use hyper::http::request::Parts;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    // Local HTTP server, `handler` called with request head and body.
+    // Return URL of server.
+    pub fn serve<F>(handler: F) -> String
+    where
+        F: Fn(&Parts, &[u8]) -> Response<Body> + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let make_service = make_service_fn(move |_conn| {
+            let handler = Arc::clone(&handler);
+            future::ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let handler = Arc::clone(&handler);
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = hyper::body::to_bytes(body).await?;
+                    Ok::<_, hyper::Error>(handler(&parts, &body))
+                }
+            }))
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    // Bitcoind RPC with results from `handler`, `Err` is RPC error code
+    pub fn serve_rpc<F>(handler: F) -> String
+    where
+        F: Fn(&str, &[Value]) -> Result<Value, i32> + Send + Sync + 'static,
+    {
+        serve(move |_parts, body| {
+            let call = |request: &Value| {
+                let method = request["method"].as_str().unwrap();
+                let params = request["params"].as_array().cloned().unwrap_or_default();
+                let (result, error) = match handler(method, &params) {
+                    Ok(result) => (result, Value::Null),
+                    Err(code) => (Value::Null, json!({ "code": code, "message": "error" })),
+                };
+                json!({ "id": request["id"], "result": result, "error": error })
+            };
+
+            let request: Value = serde_json::from_slice(body).unwrap();
+            let response = match request.as_array() {
+                Some(requests) => Value::Array(requests.iter().map(call).collect()),
+                None => call(&request),
+            };
+            Response::new(Body::from(response.to_string()))
+        })
+    }
+
+    // URL where nobody listen
+    fn closed_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/", listener.local_addr().unwrap())
+    }
+
+    // Node with chain where hash depends from `fork` and height
+    fn serve_node(fork: u8, tip: u32) -> String {
+        let hash = move |height: u32| format!("{:02x}{:062x}", fork, height);
+        serve_rpc(move |method, params| match method {
+            "getblockchaininfo" => Ok(json!({
+                "chain": "main",
+                "blocks": tip,
+                "bestblockhash": hash(tip),
+            })),
+            "getblockhash" => match params[0].as_u64().unwrap() as u32 {
+                height if height <= tip => Ok(json!(hash(height))),
+                _ => Err(-8),
+            },
+            "getrawmempool" => Ok(json!([hash(tip)])),
+            _ => Err(-32601),
+        })
+    }
+
+    pub fn http_config() -> HttpConfig {
+        HttpConfig {
+            connect_timeout: Duration::from_millis(250),
+            request_timeout: Duration::from_secs(1),
+            chaininfo_timeout: Duration::from_millis(250),
+            block_timeout_per_mb: Duration::from_secs(1),
+            pool_size: 1,
+            max_requests: 1,
+        }
+    }
+
+    pub fn bitcoind(urls: &[&str]) -> Bitcoind {
+        let (coin, chain) = CoinParams::get("bitcoin", "main").unwrap();
+        let http = http_config();
+        let nodes = urls
+            .iter()
+            .map(|url| Node::new(url, None, http, coin.rest_blocks).unwrap())
+            .collect();
+        Bitcoind {
+            coin,
+            chain,
+            raw_format: true,
+            http,
+            block_size: AtomicU32::new(0),
+            nodes,
+            next_node: AtomicUsize::new(0),
+            zmq: None,
+            files: None,
+            shutdown: Arc::new(Shutdown::new()),
+        }
+    }
+
+    #[test]
+    fn bitcoind_raw_addresses() {
+        let bitcoind = bitcoind(&["http://localhost:8332/"]);
+        let expected = vec!["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_owned()];
+
+        let data = hex::decode(raw::tests::GENESIS_BLOCK).unwrap();
+        let block = bitcoind.decode_block(&data, 0).unwrap();
+        assert_eq!(block.transactions[0].outputs[0].script.addresses, expected);
+
+        // Header and transactions count before coinbase
+        let tx = bitcoind.decode_transaction(&data[81..]).unwrap();
+        assert_eq!(tx.outputs[0].script.addresses, expected);
+    }
+
+    #[tokio::test]
+    async fn bitcoind_raw_transactions() {
+        let data = hex::decode(raw::tests::GENESIS_BLOCK).unwrap();
+        let tx = hex::encode(&data[81..]);
+        let txid = bitcoind(&["http://localhost:8332/"])
+            .decode_transaction(&data[81..])
+            .unwrap()
+            .txid;
+
+        let calls = Arc::new(AtomicU32::new(0));
+        let calls2 = Arc::clone(&calls);
+        let url = serve_rpc(move |method, params| match method {
+            "getrawtransaction" => {
+                calls2.fetch_add(1, Ordering::Relaxed);
+                match params[0].as_str().unwrap() {
+                    value if value == hex::encode(txid) => Ok(json!(tx)),
+                    _ => Err(-5),
+                }
+            }
+            _ => Err(-32601),
+        });
+        let bitcoind = bitcoind(&[&url]);
+
+        // Both transactions requested in one batch, unknown transaction is `None`
+        let txs = bitcoind
+            .get_raw_transactions(&[H256::zero(), txid])
+            .await
+            .unwrap();
+        assert!(txs[0].is_none());
+        assert_eq!(txs[1].as_ref().unwrap().txid, txid);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn bitcoind_nodes_order() {
+        let bitcoind = bitcoind(&["http://a/", "http://b/", "http://c/"]);
+        let order = |height| {
+            let nodes = bitcoind.nodes_order(height);
+            let hosts = nodes.iter().map(|node| node.url.host_str().unwrap());
+            hosts.collect::<Vec<_>>().join("")
+        };
+
+        // Round-robin, failed nodes are last
+        assert_eq!(order(None), "abc");
+        assert_eq!(order(None), "bca");
+        bitcoind.nodes[1].set_failed(true);
+        assert_eq!(order(None), "cab");
+        assert_eq!(order(None), "acb");
+
+        // Only nodes with block, all if nobody have it
+        bitcoind.nodes[0].set_tip_height(Some(10));
+        bitcoind.nodes[1].set_tip_height(Some(20));
+        bitcoind.nodes[2].set_tip_height(Some(15));
+        assert_eq!(order(Some(12)), "cb");
+        assert_eq!(order(Some(16)), "b");
+        assert_eq!(order(Some(21)), "acb");
+    }
+
+    #[tokio::test]
+    async fn bitcoind_request_failover() {
+        let (closed, node) = (closed_url(), serve_node(0, 10));
+        let bitcoind = bitcoind(&[&closed, &node]);
+
+        // First node failed, result from second
+        let txids = bitcoind.get_raw_mempool().await.unwrap();
+        assert_eq!(txids.len(), 1);
+        assert!(!bitcoind.nodes[0].is_healthy());
+        assert!(bitcoind.nodes[1].is_healthy());
+
+        // Healthy node first, failed node is not requested
+        for _ in 0..2 {
+            let order = bitcoind.nodes_order(None);
+            assert_eq!(order[0].url, bitcoind.nodes[1].url);
+            bitcoind.get_raw_mempool().await.unwrap();
+        }
+
+        // Not retryable error returned immediately
+        let result = bitcoind.get_raw_transactions(&[H256::zero()]).await;
+        assert!(matches!(result, Err(BitcoindError::ResultRPC(_))));
+    }
+
+    #[tokio::test]
+    async fn bitcoind_nodes_tips() {
+        let urls = [serve_node(0, 10), serve_node(0, 20), serve_node(1, 15)];
+        let bitcoind = bitcoind(&[&urls[0], &urls[1], &urls[2]]);
+
+        // Best node info, node on other chain is not used
+        let info = bitcoind.get_blockchain_info().await.unwrap();
+        assert_eq!(info.blocks, 20);
+        assert!(bitcoind.nodes[0].has_block(10));
+        assert!(!bitcoind.nodes[0].has_block(11));
+        assert!(bitcoind.nodes[1].has_block(20));
+        assert!(!bitcoind.nodes[2].has_block(0));
+
+        // Hashes after tip of first node only from best node
+        for _ in 0..3 {
+            let hashes = bitcoind.get_block_hashes(12..14).await.unwrap();
+            assert!(hashes.iter().all(|hash| hash.is_some()));
+            let node = bitcoind.pin_node(12);
+            assert_eq!(node.node.url, bitcoind.nodes[1].url);
+            assert_eq!(node.get_block_hash(13).await.unwrap(), hashes[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn bitcoind_nodes_tips_errors() {
+        // Best node without `getblockhash`, node with invalid response
+        let best = serve_rpc(|method, _params| match method {
+            "getblockchaininfo" => Ok(json!({
+                "chain": "main",
+                "blocks": 20,
+                "bestblockhash": format!("{:064x}", 20),
+            })),
+            _ => Err(-32601),
+        });
+        let invalid = serve_rpc(|_method, _params| Ok(json!({ "chain": "main" })));
+        let urls = [serve_node(0, 10), best, invalid];
+        let mut bitcoind = bitcoind(&[&urls[0], &urls[1], &urls[2]]);
+        bitcoind.nodes[2].set_tip_height(Some(5));
+
+        // Info from best node, other nodes are off-tip
+        let info = bitcoind.get_blockchain_info().await.unwrap();
+        assert_eq!(info.blocks, 20);
+        assert!(!bitcoind.nodes[0].has_block(0));
+        assert!(bitcoind.nodes[1].has_block(20));
+        assert!(!bitcoind.nodes[2].has_block(0));
+        assert!(!bitcoind.nodes[1].is_healthy());
+        assert!(!bitcoind.nodes[2].is_healthy());
+
+        // Error only if no node responded
+        let bitcoind = Bitcoind {
+            nodes: vec![bitcoind.nodes.remove(2)],
+            ..bitcoind
+        };
+        match bitcoind.get_blockchain_info().await {
+            Err(BitcoindError::ResponseParse(_)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
 }
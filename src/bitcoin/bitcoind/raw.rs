@@ -0,0 +1,578 @@
+// Decode consensus-serialized blocks and transactions, much faster than
+// parse verbose JSON from bitcoind.
+// See https://en.bitcoin.it/wiki/Protocol_documentation#block
+
+use sha2::{Digest, Sha256};
+
+use super::error::{BitcoindError, BitcoindResult};
+use super::json::{
+    Block, Transaction, TransactionInput, TransactionOutput, TransactionOutputScript,
+};
+use crate::bitcoin::amount::Amount;
+use crate::fixed_hash::H256;
+
+// Serialization differences in bitcoin forks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Format {
+    // Merged mining proof after header, if version have flag (Dogecoin)
+    pub auxpow: bool,
+    // MimbleWimble extension block after transactions (Litecoin)
+    pub mweb: bool,
+    // Token data before output script (Bitcoin Cash)
+    pub cashtokens: bool,
+}
+
+static AUXPOW_VERSION_FLAG: u32 = 1 << 8;
+
+// Litecoin transaction flag for MWEB data, witness flag is 1
+static MWEB_TX_FLAG: u8 = 8;
+
+static CASHTOKENS_PREFIX: u8 = 0xef;
+static CASHTOKENS_HAS_AMOUNT: u8 = 0x10;
+static CASHTOKENS_HAS_COMMITMENT: u8 = 0x40;
+
+// Decode block, height and next block hash are not part of serialized block.
+pub fn decode_block(data: &[u8], height: u32, format: Format) -> BitcoindResult<Block> {
+    let mut reader = Reader::new(data, format);
+
+    let header = reader.read_bytes(80)?;
+    let version = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let prev_hash = read_hash(&header[4..36]);
+    let time = u32::from_le_bytes([header[68], header[69], header[70], header[71]]);
+
+    if format.auxpow && version & AUXPOW_VERSION_FLAG != 0 {
+        reader.skip_auxpow()?;
+    }
+
+    let count = reader.read_varint()?;
+    let mut transactions = Vec::with_capacity(std::cmp::min(count, data.len() / 60));
+    for _ in 0..count {
+        transactions.push(reader.read_transaction()?);
+    }
+
+    // Extension block follows transactions if last one is HogEx
+    if format.mweb && count >= 2 && reader.hogex {
+        reader.skip_mweb_block(height)?;
+    }
+    reader.finish()?;
+
+    Ok(Block {
+        height,
+        hash: double_sha256(header),
+        prev_hash: if prev_hash.is_zero() {
+            None
+        } else {
+            Some(prev_hash)
+        },
+        next_hash: None,
+        transactions,
+        size: data.len() as u32,
+        time,
+    })
+}
+
+// Decode single transaction
+pub fn decode_transaction(data: &[u8], format: Format) -> BitcoindResult<Transaction> {
+    let mut reader = Reader::new(data, format);
+    reader.mweb_end = data.len().checked_sub(4);
+    let tx = reader.read_transaction()?;
+    reader.finish()?;
+    Ok(tx)
+}
+
+// Hash in same byte order as in bitcoind RPC (reversed)
+pub fn double_sha256(data: &[u8]) -> H256 {
+    let mut hash = H256::from_slice(&Sha256::digest(&Sha256::digest(data)));
+    hash.0.reverse();
+    hash
+}
+
+pub fn read_hash(data: &[u8]) -> H256 {
+    let mut hash = H256::from_slice(data);
+    hash.0.reverse();
+    hash
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: Format,
+    // Last read transaction is HogEx (Litecoin)
+    hogex: bool,
+    // MWEB transaction data do not have own length, but for single
+    // transaction it's followed only by locktime
+    mweb_end: Option<usize>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], format: Format) -> Reader<'a> {
+        Reader {
+            data,
+            pos: 0,
+            format,
+            hogex: false,
+            mweb_end: None,
+        }
+    }
+
+    fn error(&self, msg: &str) -> BitcoindError {
+        BitcoindError::ResponseDecode(format!("{} at position {}", msg, self.pos))
+    }
+
+    fn finish(&self) -> BitcoindResult<()> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(self.error("Unexpected data"))
+        }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> BitcoindResult<&'a [u8]> {
+        match self.pos.checked_add(len) {
+            Some(end) if end <= self.data.len() => {
+                let bytes = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(bytes)
+            }
+            _ => Err(self.error("Unexpected end of data")),
+        }
+    }
+
+    fn read_u8(&mut self) -> BitcoindResult<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> BitcoindResult<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&mut self) -> BitcoindResult<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_compact_size(&mut self) -> BitcoindResult<u64> {
+        Ok(match self.read_u8()? {
+            0xfd => {
+                let bytes = self.read_bytes(2)?;
+                u16::from_le_bytes([bytes[0], bytes[1]]) as u64
+            }
+            0xfe => self.read_u32()? as u64,
+            0xff => self.read_u64()?,
+            value => value as u64,
+        })
+    }
+
+    fn read_varint(&mut self) -> BitcoindResult<usize> {
+        let value = self.read_compact_size()?;
+
+        // Size can not be greater than data itself
+        if value > self.data.len() as u64 {
+            return Err(self.error("Invalid size"));
+        }
+        Ok(value as usize)
+    }
+
+    // Bitcoin Core `VARINT` (MSB base-128), not same as compact size
+    fn read_msb_varint(&mut self) -> BitcoindResult<u64> {
+        let mut value = 0u64;
+        loop {
+            let byte = self.read_u8()?;
+            value = value
+                .checked_mul(128)
+                .map(|value| value | (byte & 0x7f) as u64)
+                .ok_or_else(|| self.error("Invalid varint"))?;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            value = value
+                .checked_add(1)
+                .ok_or_else(|| self.error("Invalid varint"))?;
+        }
+    }
+
+    fn read_var_bytes(&mut self) -> BitcoindResult<&'a [u8]> {
+        let len = self.read_varint()?;
+        self.read_bytes(len)
+    }
+
+    // Transaction with optional witness (BIP144), txid calculated without
+    // witness data, hash (wtxid) with it.
+    fn read_transaction(&mut self) -> BitcoindResult<Transaction> {
+        let start = self.pos;
+
+        self.read_u32()?; // version
+        let mut inputs_count = self.read_varint()?;
+        let has_witness = inputs_count == 0;
+        let mut witness_start = 0;
+        let mut flags = 0;
+        if has_witness {
+            flags = self.read_u8()?;
+            let allowed = if self.format.mweb {
+                1 | MWEB_TX_FLAG
+            } else {
+                1
+            };
+            if flags == 0 || flags & !allowed != 0 {
+                return Err(self.error("Invalid witness flag"));
+            }
+            witness_start = self.pos;
+            inputs_count = self.read_varint()?;
+        }
+
+        let mut inputs = Vec::with_capacity(inputs_count);
+        for _ in 0..inputs_count {
+            let prev_hash = read_hash(self.read_bytes(32)?);
+            let vout = self.read_u32()?;
+            let script = self.read_var_bytes()?;
+            self.read_u32()?; // sequence
+
+            inputs.push(if prev_hash.is_zero() && vout == u32::MAX {
+                TransactionInput::Coinbase {
+                    hex: script.to_vec(),
+                }
+            } else {
+                TransactionInput::Usual {
+                    txid: Some(prev_hash),
+                    vout,
+                }
+            });
+        }
+
+        let outputs_count = self.read_varint()?;
+        let mut outputs = Vec::with_capacity(outputs_count);
+        for _ in 0..outputs_count {
+            let value = self.read_u64()?;
+            let value = Amount::from_sat(value).ok_or_else(|| self.error("Invalid value"))?;
+            let mut script = self.read_var_bytes()?;
+            if self.format.cashtokens {
+                script = self.strip_token_prefix(script)?;
+            }
+            outputs.push(TransactionOutput {
+                value,
+                script: TransactionOutputScript {
+                    hex: script.to_vec(),
+                    addresses: vec![],
+                },
+            });
+        }
+        let outputs_end = self.pos;
+
+        if flags & 1 != 0 {
+            for _ in 0..inputs_count {
+                for _ in 0..self.read_varint()? {
+                    self.read_var_bytes()?;
+                }
+            }
+        }
+
+        // HogEx have empty MWEB data, non-empty data is possible only in
+        // mempool, where we skip it, because only canonical part is indexed
+        self.hogex = false;
+        if flags & MWEB_TX_FLAG != 0 {
+            match (self.read_u8()?, self.mweb_end) {
+                (0, _) => self.hogex = true,
+                (1, Some(end)) if end >= self.pos => self.pos = end,
+                _ => return Err(self.error("Invalid MWEB transaction data")),
+            }
+        }
+
+        let locktime = self.read_bytes(4)?;
+        let raw = &self.data[start..self.pos];
+
+        let txid = if has_witness {
+            // version + inputs and outputs (without marker and flag) + locktime
+            let mut stripped = Vec::with_capacity(raw.len());
+            stripped.extend_from_slice(&raw[0..4]);
+            stripped.extend_from_slice(&self.data[witness_start..outputs_end]);
+            stripped.extend_from_slice(locktime);
+            double_sha256(&stripped)
+        } else {
+            double_sha256(raw)
+        };
+
+        Ok(Transaction {
+            txid,
+            hash: double_sha256(raw),
+            hex: raw.to_vec(),
+            inputs,
+            outputs,
+        })
+    }
+
+    // Parent chain coinbase transaction with merkle branches and parent
+    // block header. Only header of our block is used for block hash.
+    fn skip_auxpow(&mut self) -> BitcoindResult<()> {
+        self.read_transaction()?;
+        self.read_bytes(32)?; // parent block hash
+        for _ in 0..2 {
+            // coinbase merkle branch, then chain merkle branch
+            let count = self.read_varint()?;
+            self.read_bytes(
+                count
+                    .checked_mul(32)
+                    .ok_or_else(|| self.error("Invalid size"))?,
+            )?;
+            self.read_u32()?; // index
+        }
+        self.read_bytes(80)?; // parent block header
+        Ok(())
+    }
+
+    // Optional extension block: header and body with MWEB inputs, outputs
+    // and kernels. Body is not used by indexer and is not parsed, but header
+    // should be valid and for same height.
+    fn skip_mweb_block(&mut self, height: u32) -> BitcoindResult<()> {
+        match self.read_u8()? {
+            0 => return Ok(()),
+            1 => {}
+            _ => return Err(self.error("Invalid MWEB block flag")),
+        }
+
+        if self.read_msb_varint()? != height as u64 {
+            return Err(self.error("Invalid MWEB block height"));
+        }
+        // Output, kernel and leafset roots, kernel and stealth offsets
+        self.read_bytes(5 * 32)?;
+        self.read_msb_varint()?; // output MMR size
+        self.read_msb_varint()?; // kernel MMR size
+
+        // Inputs, outputs and kernels counts at least
+        if self.data.len() - self.pos < 3 {
+            return Err(self.error("Unexpected end of MWEB block"));
+        }
+        self.pos = self.data.len();
+        Ok(())
+    }
+
+    // Token category, bitfield, optional NFT commitment and optional amount
+    // placed before locking script, with prefix byte.
+    fn strip_token_prefix(&self, script: &'a [u8]) -> BitcoindResult<&'a [u8]> {
+        if script.first() != Some(&CASHTOKENS_PREFIX) {
+            return Ok(script);
+        }
+
+        let mut reader = Reader::new(&script[1..], self.format);
+        let error = |_| self.error("Invalid token prefix");
+        reader.read_bytes(32).map_err(error)?; // category
+        let bitfield = reader.read_u8().map_err(error)?;
+        if bitfield & CASHTOKENS_HAS_COMMITMENT != 0 {
+            reader.read_var_bytes().map_err(error)?;
+        }
+        if bitfield & CASHTOKENS_HAS_AMOUNT != 0 {
+            reader.read_compact_size().map_err(error)?;
+        }
+        Ok(&reader.data[reader.pos..])
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub static GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    fn hash(value: &str) -> H256 {
+        let mut hash = H256::zero();
+        hex::decode_to_slice(value, &mut hash.0 as &mut [u8]).unwrap();
+        hash
+    }
+
+    #[test]
+    fn raw_decode_genesis_block() {
+        let data = hex::decode(GENESIS_BLOCK).unwrap();
+        let block = decode_block(&data, 0, Format::default()).unwrap();
+
+        let block_hash = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+        assert_eq!(block.hash, hash(block_hash));
+        assert_eq!(block.prev_hash, None);
+        assert_eq!(block.time, 1_231_006_505);
+        assert_eq!(block.size, 285);
+        assert_eq!(block.transactions.len(), 1);
+
+        let tx = &block.transactions[0];
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        assert_eq!(tx.txid, hash(txid));
+        assert_eq!(tx.hash, tx.txid);
+        assert!(matches!(tx.inputs[0], TransactionInput::Coinbase { .. }));
+        assert_eq!(tx.outputs.len(), 1);
+        assert_eq!(
+            tx.outputs[0].value,
+            Amount::from_sat(5_000_000_000).unwrap()
+        );
+        assert_eq!(tx.outputs[0].script.hex.len(), 67);
+    }
+
+    #[test]
+    fn raw_decode_witness_transaction() {
+        let input = format!("{}00000000{}ffffffff", "11".repeat(32), "00");
+        let output = format!("e803000000000000160014{}", "22".repeat(20));
+        // Two items for one input: `0102` and `03`
+        let witness = "020201020103";
+        let legacy = format!("0200000001{}01{}00000000", input, output);
+        // `0001` is marker and flag
+        let segwit = format!("02000000000101{}01{}{}00000000", input, output, witness);
+
+        let legacy = decode_transaction(&hex::decode(legacy).unwrap(), Format::default()).unwrap();
+        let segwit = decode_transaction(&hex::decode(segwit).unwrap(), Format::default()).unwrap();
+
+        assert_eq!(legacy.hash, legacy.txid);
+        assert_eq!(segwit.txid, legacy.txid);
+        assert_ne!(segwit.hash, segwit.txid);
+        assert!(matches!(
+            segwit.inputs[0],
+            TransactionInput::Usual { vout: 0, txid: Some(txid) } if txid == H256::repeat_byte(0x11)
+        ));
+        assert_eq!(segwit.outputs[0].value, Amount::from_sat(1_000).unwrap());
+    }
+
+    #[test]
+    fn raw_decode_invalid_data() {
+        let data = hex::decode(GENESIS_BLOCK).unwrap();
+        assert!(decode_block(&data[0..data.len() - 1], 0, Format::default()).is_err());
+
+        let mut data = data;
+        data.push(0);
+        assert!(decode_block(&data, 0, Format::default()).is_err());
+    }
+
+    #[test]
+    fn raw_decode_auxpow_block() {
+        let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+        let (header, transactions) = genesis.split_at(80);
+        let coinbase = &transactions[1..];
+
+        // Header with AuxPoW flag, proof use genesis coinbase and header as parent
+        let mut data = header.to_vec();
+        data[1] |= 1;
+        data.extend_from_slice(coinbase);
+        data.extend_from_slice(&[0; 32]);
+        data.extend_from_slice(&[1]);
+        data.extend_from_slice(&[0x11; 32]);
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0]);
+        data.extend_from_slice(header);
+        data.extend_from_slice(transactions);
+
+        let format = Format {
+            auxpow: true,
+            ..Format::default()
+        };
+        let block = decode_block(&data, 0, format).unwrap();
+        assert_eq!(block.hash, double_sha256(&data[0..80]));
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.size, data.len() as u32);
+
+        assert!(decode_block(&data, 0, Format::default()).is_err());
+    }
+
+    #[test]
+    fn raw_decode_mweb_transaction() {
+        let input = format!("{}00000000{}ffffffff", "11".repeat(32), "00");
+        let output = format!("e803000000000000160014{}", "22".repeat(20));
+        let legacy = format!("0200000001{}01{}00000000", input, output);
+        // HogEx: flag `08` and empty MWEB data before locktime
+        let hogex = format!("02000000000801{}01{}0000000000", input, output);
+
+        let format = Format {
+            mweb: true,
+            ..Format::default()
+        };
+        let legacy = decode_transaction(&hex::decode(legacy).unwrap(), format).unwrap();
+        let hogex = decode_transaction(&hex::decode(&hogex).unwrap(), format).unwrap();
+        assert_eq!(hogex.txid, legacy.txid);
+
+        assert!(decode_transaction(&hogex.hex, Format::default()).is_err());
+
+        // Mempool transaction with MWEB data, canonical part is decoded
+        let mweb = format!(
+            "02000000000801{}01{}01{}00000000",
+            input,
+            output,
+            "33".repeat(100)
+        );
+        let mweb = decode_transaction(&hex::decode(&mweb).unwrap(), format).unwrap();
+        assert_eq!(mweb.txid, legacy.txid);
+        assert_eq!(mweb.outputs.len(), 1);
+
+        let invalid = format!("02000000000801{}01{}0200000000", input, output);
+        assert!(decode_transaction(&hex::decode(&invalid).unwrap(), format).is_err());
+    }
+
+    #[test]
+    fn raw_decode_mweb_block() {
+        let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+        let (header, transactions) = genesis.split_at(80);
+        let coinbase = &transactions[1..];
+        let input = format!("{}00000000{}ffffffff", "11".repeat(32), "00");
+        let output = format!("e803000000000000160014{}", "22".repeat(20));
+        let hogex = hex::decode(format!("02000000000801{}01{}0000000000", input, output)).unwrap();
+
+        // Height 300 as VARINT is `812c`, empty body
+        let mweb_header = format!("812c{}0102", "44".repeat(5 * 32));
+        let build = |last: &[u8], mweb: &str| {
+            let mut data = header.to_vec();
+            data.push(2);
+            data.extend_from_slice(coinbase);
+            data.extend_from_slice(last);
+            data.extend(hex::decode(mweb).unwrap());
+            data
+        };
+
+        let format = Format {
+            mweb: true,
+            ..Format::default()
+        };
+        let data = build(&hogex, &format!("01{}000000", mweb_header));
+        let block = decode_block(&data, 300, format).unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        assert_eq!(block.size, data.len() as u32);
+        assert!(decode_block(&data, 301, format).is_err());
+        assert!(decode_block(&data, 300, Format::default()).is_err());
+
+        // Truncated header or body, invalid flag
+        let truncated = build(&hogex, &format!("01{}", mweb_header));
+        assert!(decode_block(&truncated, 300, format).is_err());
+        let truncated = build(&hogex, &format!("01{}", &mweb_header[0..100]));
+        assert!(decode_block(&truncated, 300, format).is_err());
+        assert!(decode_block(&build(&hogex, "02"), 300, format).is_err());
+        assert!(decode_block(&build(&hogex, "00"), 300, format).is_ok());
+
+        // Without HogEx there is no extension block
+        assert!(decode_block(&build(coinbase, ""), 300, format).is_ok());
+        assert!(decode_block(&build(coinbase, "00"), 300, format).is_err());
+    }
+
+    #[test]
+    fn raw_decode_cashtokens_output() {
+        let input = format!("{}00000000{}ffffffff", "11".repeat(32), "00");
+        let script = format!("76a914{}88ac", "22".repeat(20));
+        // Category, bitfield with commitment and amount, commitment `0102`, amount 1000
+        let token = format!("ef{}70020102fde803", "33".repeat(32));
+        let output = format!(
+            "e803000000000000{:02x}{}{}",
+            (token.len() + script.len()) / 2,
+            token,
+            script
+        );
+        let data = hex::decode(format!("0200000001{}01{}00000000", input, output)).unwrap();
+
+        let tx = decode_transaction(
+            &data,
+            Format {
+                cashtokens: true,
+                ..Format::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(hex::encode(&tx.outputs[0].script.hex), script);
+
+        let tx = decode_transaction(&data, Format::default()).unwrap();
+        assert_eq!(
+            tx.outputs[0].script.hex.len(),
+            (token.len() + script.len()) / 2
+        );
+    }
+}
@@ -3,18 +3,24 @@
 // See issue in bitcoin repo: https://github.com/bitcoin/bitcoin/issues/15925
 
 use std::fmt;
-use std::time::Duration;
+use std::sync::Arc;
 
-use reqwest::{header, redirect, Client, ClientBuilder, RequestBuilder};
+use reqwest::{Client, Response};
+use tokio::sync::Semaphore;
 use url::Url;
 
 use super::error::{BitcoindError, BitcoindResult};
+use super::http::{HttpConfig, Timeout};
 use super::json::{Block, BlockchainInfo};
+use super::stream;
 use crate::fixed_hash::H256;
 
 pub struct RESTClient {
     client: Client,
     url: Url,
+    http: HttpConfig,
+    // Shared with RPC client of same node
+    requests: Arc<Semaphore>,
 }
 
 impl fmt::Debug for RESTClient {
@@ -26,40 +32,44 @@ impl fmt::Debug for RESTClient {
 }
 
 impl RESTClient {
-    pub fn new(url: Url) -> BitcoindResult<RESTClient> {
-        let mut headers = header::HeaderMap::with_capacity(1);
-        headers.insert(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("applicaiton/json"),
-        );
-
-        let client = ClientBuilder::new()
-            .connect_timeout(Duration::from_millis(250))
-            .timeout(Duration::from_secs(30))
-            .default_headers(headers)
-            .no_gzip()
-            .redirect(redirect::Policy::none());
-
+    pub fn new(url: Url, http: HttpConfig, requests: Arc<Semaphore>) -> BitcoindResult<RESTClient> {
         Ok(RESTClient {
-            client: client.build().map_err(BitcoindError::Reqwest)?,
+            client: http.build_client()?,
             url,
+            http,
+            requests,
         })
     }
 
-    fn request(&self, path: &str) -> RequestBuilder {
+    async fn send(&self, path: &str, timeout: Timeout) -> BitcoindResult<Response> {
         let mut url = self.url.clone();
         url.set_path(path);
-        self.client.get(url)
+        let res_fut = self.client.get(url).timeout(timeout.duration()).send();
+        res_fut.await.map_err(|e| self.http.map_error(e, timeout))
     }
 
-    pub async fn get_blockchain_info(&self) -> BitcoindResult<BlockchainInfo> {
-        let timeout = Duration::from_millis(250);
+    async fn read_body(&self, res: Response, timeout: Timeout) -> BitcoindResult<Vec<u8>> {
+        let body_fut = res.bytes();
+        let body = body_fut
+            .await
+            .map_err(|e| self.http.map_error(e, timeout))?;
+        Ok(body.to_vec())
+    }
+
+    // Status code and body
+    async fn request(&self, path: &str, timeout: Timeout) -> BitcoindResult<(u16, Vec<u8>)> {
+        let _permit = self.requests.acquire().await;
 
-        let res_fut = self.request("rest/chaininfo.json").timeout(timeout).send();
-        let res = res_fut.await.map_err(BitcoindError::Reqwest)?;
+        let res = self.send(path, timeout).await?;
         let status_code = res.status().as_u16();
+        let body = self.read_body(res, timeout).await?;
+        Ok((status_code, body))
+    }
 
-        let body = res.bytes().await.map_err(BitcoindError::Reqwest)?;
+    pub async fn get_blockchain_info(&self) -> BitcoindResult<BlockchainInfo> {
+        let (status_code, body) = self
+            .request("rest/chaininfo.json", self.http.chaininfo())
+            .await?;
 
         match status_code {
             200 => serde_json::from_slice(&body).map_err(BitcoindError::ResponseParse),
@@ -70,29 +80,28 @@ impl RESTClient {
         }
     }
 
-    pub async fn get_block_by_hash(&self, hash: H256) -> BitcoindResult<Option<Block>> {
-        let res_fut = self
-            .request(&format!("rest/block/{}.json", hex::encode(hash)))
-            .send();
-        let res = res_fut.await.map_err(BitcoindError::Reqwest)?;
+    pub async fn get_block_by_hash(
+        &self,
+        hash: H256,
+        timeout: Timeout,
+    ) -> BitcoindResult<Option<Block>> {
+        let _permit = self.requests.acquire().await;
 
+        let path = format!("rest/block/{}.json", hex::encode(hash));
+        let res = self.send(&path, timeout).await?;
         let status_code = res.status().as_u16();
-        if status_code == 404 {
-            return Ok(None);
-        }
-
-        // Should be serde_json::from_reader
-        let body_fut = res.bytes();
-        let body = body_fut.await.map_err(BitcoindError::Reqwest)?;
         if status_code != 200 {
+            let body = self.read_body(res, timeout).await?;
+            if status_code == 404 && !is_pruned_message(&body) {
+                return Ok(None);
+            }
             let msg = String::from_utf8_lossy(&body).trim().to_owned();
             return Err(BitcoindError::ResultRest(status_code, msg));
         }
 
         // In `release` can take up to 60ms (and more), for `debug` ~10x more time comapre to `release`.
         // See also https://github.com/fanatid/bitcoin-rust-learning
-        let parsed = serde_json::from_slice(&body);
-        let block: Block = parsed.map_err(BitcoindError::ResponseParse)?;
+        let block: Block = stream::from_response(res, self.http, timeout).await?;
 
         // Check that received block match to requested
         if block.hash != hash {
@@ -101,4 +110,27 @@ impl RESTClient {
 
         Ok(Some(block))
     }
+
+    pub async fn get_raw_block_by_hash(
+        &self,
+        hash: H256,
+        timeout: Timeout,
+    ) -> BitcoindResult<Option<Vec<u8>>> {
+        let path = format!("rest/block/{}.bin", hex::encode(hash));
+        let (status_code, body) = self.request(&path, timeout).await?;
+        if status_code == 404 && !is_pruned_message(&body) {
+            return Ok(None);
+        }
+        if status_code != 200 {
+            let msg = String::from_utf8_lossy(&body).trim().to_owned();
+            return Err(BitcoindError::ResultRest(status_code, msg));
+        }
+
+        Ok(Some(body))
+    }
+}
+
+// Pruned block is also 404, but with message: "{hash} not available (pruned data)"
+fn is_pruned_message(body: &[u8]) -> bool {
+    String::from_utf8_lossy(body).contains("(pruned data)")
 }
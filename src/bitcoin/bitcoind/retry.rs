@@ -0,0 +1,95 @@
+// Retry policy for requests to bitcoind: exponential backoff with jitter.
+// Only errors where node can be back (restart, network issue) are retried,
+// errors like mismatched result are returned immediately.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::error::BitcoindError;
+
+// Delays: 250ms, 500ms, 1s, 2s ... up to 30s, with all attempts it's about 3
+// minutes, should be enough for bitcoind restart
+static RETRY_INITIAL_DELAY: Duration = Duration::from_millis(250);
+static RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+pub static RETRY_ATTEMPTS: u32 = 12;
+
+// Errors where request can be repeated later or on other node: connection
+// errors and timeouts, HTTP 5xx from REST and client warming up (code "-28")
+pub fn is_retryable(error: &BitcoindError) -> bool {
+    match error {
+        BitcoindError::Reqwest(_) => true,
+        // Block timeout grow with every attempt, see `HttpConfig::block`
+        BitcoindError::Timeout(_) => true,
+        // Cookie file removed while bitcoind restarting
+        BitcoindError::Cookie(_, _) => true,
+        BitcoindError::ResultRest(code, _msg) => *code >= 500,
+        BitcoindError::ResultRPC(error) => error.code == -28,
+        BitcoindError::Shutdown(_)
+        | BitcoindError::UnsupportedChain(_, _)
+        | BitcoindError::InvalidUrl(_)
+        | BitcoindError::InvalidUrlScheme(_)
+        | BitcoindError::CookieInvalid(_)
+        | BitcoindError::Unauthorized
+        | BitcoindError::ResponseParse(_)
+        | BitcoindError::ResponseDecode(_)
+        | BitcoindError::BlockFiles(_)
+        | BitcoindError::TaskJoin(_)
+        | BitcoindError::NonceMismatch
+        | BitcoindError::ZMQ(_)
+        | BitcoindError::ZMQInvalidMessage(_)
+        | BitcoindError::BlockPruned(_)
+        | BitcoindError::ResultNotFound
+        | BitcoindError::ResultMismatch
+        | BitcoindError::ClientInvalidX(_, _, _)
+        | BitcoindError::ClientInvalidVersionX(_, _)
+        | BitcoindError::ClientMismatch => false,
+    }
+}
+
+// Delay before next attempt (`attempt` starts from 1), random in range
+// `[delay / 2, delay]`, so requests from sync threads are not repeated at
+// same time
+pub fn get_delay(attempt: u32) -> Duration {
+    let delay = get_delay_max(attempt).as_nanos() as u64;
+    let nanos = rand::thread_rng().gen_range(delay / 2, delay + 1);
+    Duration::from_nanos(nanos)
+}
+
+fn get_delay_max(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    RETRY_INITIAL_DELAY
+        .checked_mul(factor)
+        .map_or(RETRY_MAX_DELAY, |delay| delay.min(RETRY_MAX_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay() {
+        assert_eq!(get_delay_max(1), Duration::from_millis(250));
+        assert_eq!(get_delay_max(2), Duration::from_millis(500));
+        assert_eq!(get_delay_max(4), Duration::from_secs(2));
+        assert_eq!(get_delay_max(8), Duration::from_secs(30));
+        assert_eq!(get_delay_max(100), Duration::from_secs(30));
+
+        for attempt in 1..RETRY_ATTEMPTS {
+            let delay = get_delay(attempt);
+            assert!(delay >= get_delay_max(attempt) / 2);
+            assert!(delay <= get_delay_max(attempt));
+        }
+
+        // Threads failed at same time should not retry together
+        let delays = (0..10).map(|_| get_delay(8)).collect::<Vec<_>>();
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+        assert!(is_retryable(&BitcoindError::ResultRest(503, String::new())));
+        assert!(!is_retryable(&BitcoindError::ResultRest(
+            404,
+            String::new()
+        )));
+        assert!(!is_retryable(&BitcoindError::ResultMismatch));
+    }
+}
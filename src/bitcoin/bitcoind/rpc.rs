@@ -1,19 +1,27 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::ops::Range;
 use std::sync::Arc;
-use std::time::Duration;
 
-use reqwest::{header, redirect, Client, ClientBuilder};
+use reqwest::{header, Client, Response as HttpResponse, StatusCode};
 use serde::Deserialize;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use url::Url;
 
+use super::auth::RPCAuth;
 use super::error::{BitcoindError, BitcoindResult};
-use super::json::{Block, BlockchainInfo, NetworkInfo, Request, Response};
+use super::http::{HttpConfig, Timeout};
+use super::json::{Block, BlockchainInfo, NetworkInfo, Request, Response, Transaction};
+use super::stream;
 use crate::fixed_hash::H256;
 
 pub struct RPCClient {
     client: Client,
     url: Url,
+    auth: RPCAuth,
+    http: HttpConfig,
+    // Shared with REST client of same node
+    requests: Arc<Semaphore>,
     req_id: Arc<Mutex<u64>>,
 }
 
@@ -28,58 +36,83 @@ impl fmt::Debug for RPCClient {
 
 impl RPCClient {
     // Construct new RPCClient for specified URL
-    pub fn new(url: Url, auth: Vec<u8>) -> BitcoindResult<RPCClient> {
-        let mut headers = header::HeaderMap::with_capacity(2);
-        headers.insert(
-            header::AUTHORIZATION,
-            header::HeaderValue::from_bytes(&auth)
-                .expect("Not possible build auth from provided username/password"),
-        );
-        headers.insert(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static("applicaiton/json"),
-        );
-
-        let client = ClientBuilder::new()
-            .connect_timeout(Duration::from_millis(250))
-            .timeout(Duration::from_secs(30))
-            .default_headers(headers)
-            .no_gzip()
-            .redirect(redirect::Policy::none());
-
+    pub fn new(
+        url: Url,
+        auth: RPCAuth,
+        http: HttpConfig,
+        requests: Arc<Semaphore>,
+    ) -> BitcoindResult<RPCClient> {
         Ok(RPCClient {
-            client: client.build().map_err(BitcoindError::Reqwest)?,
+            client: http.build_client()?,
             url,
+            auth,
+            http,
+            requests,
             req_id: Arc::new(Mutex::new(0)),
         })
     }
 
     async fn get_next_req_id(&self) -> u64 {
+        self.get_next_req_ids(1).await[0]
+    }
+
+    async fn get_next_req_ids(&self, count: usize) -> Vec<u64> {
         let mut req_id = self.req_id.lock().await;
-        *req_id = req_id.wrapping_add(1);
-        *req_id
+        (0..count)
+            .map(|_| {
+                *req_id = req_id.wrapping_add(1);
+                *req_id
+            })
+            .collect()
     }
 
-    async fn request<T: serde::de::DeserializeOwned>(
+    async fn send(&self, body: Vec<u8>, timeout: Timeout) -> BitcoindResult<HttpResponse> {
+        let res_fut = self
+            .client
+            .post(self.url.clone())
+            .header(header::AUTHORIZATION, self.auth.header()?)
+            .timeout(timeout.duration())
+            .body(body)
+            .send();
+        res_fut.await.map_err(|e| self.http.map_error(e, timeout))
+    }
+
+    async fn request<T: serde::de::DeserializeOwned + Send + 'static>(
         &self,
         body: Vec<u8>,
-    ) -> BitcoindResult<Response<T>> {
-        let res_fut = self.client.post(self.url.clone()).body(body).send();
-        let res = res_fut.await.map_err(BitcoindError::Reqwest)?;
+        timeout: Timeout,
+    ) -> BitcoindResult<T> {
+        let _permit = self.requests.acquire().await;
+
+        let mut res = self.send(body.clone(), timeout).await?;
+
+        // Cookie is changed on bitcoind restart, re-read and try again
+        if res.status() == StatusCode::UNAUTHORIZED && self.auth.reload()?.is_some() {
+            res = self.send(body, timeout).await?;
+        }
+        if res.status() == StatusCode::UNAUTHORIZED {
+            return Err(BitcoindError::Unauthorized);
+        }
 
         // We ignore status, because expect error information in the body
         // let status = res.status();
 
-        // Should be serde_json::from_reader
-        let body_fut = res.bytes();
-        let body = body_fut.await.map_err(BitcoindError::Reqwest)?;
-        serde_json::from_slice(&body).map_err(BitcoindError::ResponseParse)
+        stream::from_response(res, self.http, timeout).await
     }
 
-    async fn call<T: serde::de::DeserializeOwned>(
+    async fn call<T: serde::de::DeserializeOwned + Send + 'static>(
         &self,
         method: &str,
         params: Option<&[serde_json::Value]>,
+    ) -> BitcoindResult<T> {
+        self.call_timeout(method, params, self.http.request()).await
+    }
+
+    async fn call_timeout<T: serde::de::DeserializeOwned + Send + 'static>(
+        &self,
+        method: &str,
+        params: Option<&[serde_json::Value]>,
+        timeout: Timeout,
     ) -> BitcoindResult<T> {
         let req_id = self.get_next_req_id().await;
 
@@ -90,17 +123,41 @@ impl RPCClient {
         })
         .expect("Invalid data for building JSON");
 
-        let data = self.request::<T>(body).await?;
+        let data = self.request::<Response<T>>(body, timeout).await?;
         if data.id != req_id {
             return Err(BitcoindError::NonceMismatch);
         }
-        if let Some(error) = data.error {
-            return Err(BitcoindError::ResultRPC(error));
-        }
-        match data.result {
-            None => Err(BitcoindError::ResultNotFound),
-            Some(result) => Ok(result),
+        response_result(data)
+    }
+
+    // Batch of calls for same method in one HTTP request. Error returned only
+    // for whole request, errors for every call are in returned list.
+    async fn call_batch<T: serde::de::DeserializeOwned + Send + 'static>(
+        &self,
+        method: &str,
+        params: &[Vec<serde_json::Value>],
+    ) -> BitcoindResult<Vec<BitcoindResult<T>>> {
+        if params.is_empty() {
+            return Ok(vec![]);
         }
+
+        let req_ids = self.get_next_req_ids(params.len()).await;
+
+        let requests = params
+            .iter()
+            .zip(req_ids.iter())
+            .map(|(params, id)| Request {
+                method,
+                params: Some(params),
+                id: *id,
+            })
+            .collect::<Vec<_>>();
+        let body = serde_json::to_vec(&requests).expect("Invalid data for building JSON");
+
+        let data = self
+            .request::<Vec<Response<T>>>(body, self.http.request())
+            .await?;
+        batch_results(&req_ids, data)
     }
 
     pub async fn get_network_info(&self) -> BitcoindResult<NetworkInfo> {
@@ -130,9 +187,119 @@ impl RPCClient {
         }
     }
 
-    pub async fn get_block_by_hash(&self, hash: H256) -> BitcoindResult<Option<Block>> {
+    // Hashes for range of heights in one request, `None` for heights after tip
+    pub async fn get_block_hashes(&self, heights: Range<u32>) -> BitcoindResult<Vec<Option<H256>>> {
+        #[derive(Debug, Deserialize)]
+        struct Response(#[serde(deserialize_with = "H256::deserialize_hex")] H256);
+
+        let params = heights
+            .map(|height| vec![height.into()])
+            .collect::<Vec<_>>();
+        let results = self.call_batch::<Response>("getblockhash", &params).await?;
+        results
+            .into_iter()
+            .map(|result| match result {
+                Ok(st) => Ok(Some(st.0)),
+                // Block height out of range
+                Err(BitcoindError::ResultRPC(error)) if error.code == -8 => Ok(None),
+                Err(error) => Err(error),
+            })
+            .collect()
+    }
+
+    pub async fn get_raw_mempool(&self) -> BitcoindResult<Vec<H256>> {
+        #[derive(Debug, Deserialize)]
+        struct Txid(#[serde(deserialize_with = "H256::deserialize_hex")] H256);
+
+        let txids = self.call::<Vec<Txid>>("getrawmempool", None).await?;
+        Ok(txids.into_iter().map(|txid| txid.0).collect())
+    }
+
+    // Transactions in one batch request, `None` if transaction not found
+    // (removed from mempool)
+    pub async fn get_raw_transactions_bytes(
+        &self,
+        txids: &[H256],
+    ) -> BitcoindResult<Vec<Option<Vec<u8>>>> {
+        #[derive(Debug, Deserialize)]
+        struct Response(#[serde(deserialize_with = "hex::deserialize")] Vec<u8>);
+
+        let params = txids
+            .iter()
+            .map(|txid| vec![hex::encode(txid).into(), false.into()])
+            .collect::<Vec<_>>();
+        let results = self
+            .call_batch::<Response>("getrawtransaction", &params)
+            .await?;
+        results
+            .into_iter()
+            .map(|result| match result {
+                Ok(tx) => Ok(Some(tx.0)),
+                Err(BitcoindError::ResultRPC(error)) if error.code == -5 => Ok(None),
+                Err(error) => Err(error),
+            })
+            .collect()
+    }
+
+    pub async fn get_raw_transactions(
+        &self,
+        txids: &[H256],
+    ) -> BitcoindResult<Vec<Option<Transaction>>> {
+        let params = txids
+            .iter()
+            .map(|txid| vec![hex::encode(txid).into(), true.into()])
+            .collect::<Vec<_>>();
+        let results = self
+            .call_batch::<Transaction>("getrawtransaction", &params)
+            .await?;
+        results
+            .into_iter()
+            .zip(txids)
+            .map(|(result, txid)| match result {
+                Ok(tx) if tx.txid == *txid => Ok(Some(tx)),
+                Ok(_) => Err(BitcoindError::ResultMismatch),
+                Err(BitcoindError::ResultRPC(error)) if error.code == -5 => Ok(None),
+                Err(error) => Err(error),
+            })
+            .collect()
+    }
+
+    pub async fn get_raw_block_by_hash(
+        &self,
+        hash: H256,
+        timeout: Timeout,
+    ) -> BitcoindResult<Option<Vec<u8>>> {
+        #[derive(Debug, Deserialize)]
+        struct Response(#[serde(deserialize_with = "hex::deserialize")] Vec<u8>);
+
+        let params = [hex::encode(hash).into(), 0.into()];
+        match self
+            .call_timeout::<Response>("getblock", Some(&params), timeout)
+            .await
+        {
+            Ok(block) => Ok(Some(block.0)),
+            Err(BitcoindError::ResultRPC(error)) => {
+                // Block not found
+                if error.code == -5 {
+                    Ok(None)
+                } else {
+                    Err(BitcoindError::ResultRPC(error))
+                }
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    pub async fn get_block_by_hash(
+        &self,
+        hash: H256,
+        timeout: Timeout,
+    ) -> BitcoindResult<Option<Block>> {
         let params = [hex::encode(hash).into(), 2.into()];
-        match self.call::<Block>("getblock", Some(&params)).await {
+        match self
+            .call_timeout::<Block>("getblock", Some(&params), timeout)
+            .await
+        {
             Ok(block) => {
                 if block.hash == hash {
                     Ok(Some(block))
@@ -152,3 +319,119 @@ impl RPCClient {
         }
     }
 }
+
+fn response_result<T>(data: Response<T>) -> BitcoindResult<T> {
+    if let Some(error) = data.error {
+        return Err(BitcoindError::ResultRPC(error));
+    }
+    match data.result {
+        None => Err(BitcoindError::ResultNotFound),
+        Some(result) => Ok(result),
+    }
+}
+
+// Responses in batch can be in any order, so we match them by id
+fn batch_results<T>(
+    req_ids: &[u64],
+    data: Vec<Response<T>>,
+) -> BitcoindResult<Vec<BitcoindResult<T>>> {
+    if data.len() != req_ids.len() {
+        return Err(BitcoindError::NonceMismatch);
+    }
+
+    let mut responses = data
+        .into_iter()
+        .map(|item| (item.id, item))
+        .collect::<HashMap<_, _>>();
+    req_ids
+        .iter()
+        .map(|id| match responses.remove(id) {
+            Some(item) => Ok(response_result(item)),
+            None => Err(BitcoindError::NonceMismatch),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex as StdMutex;
+
+    use hyper::{Body, Response as HttpResponse, StatusCode as HttpStatusCode};
+
+    use super::*;
+    use crate::bitcoin::bitcoind::tests::{http_config, serve};
+
+    #[tokio::test]
+    async fn rpc_cookie_reload() {
+        let path = std::env::temp_dir().join(format!("telescope-rpc-{}", std::process::id()));
+        fs::write(&path, "__cookie__:one").unwrap();
+
+        // Node accept only current password, count all requests
+        let password = Arc::new(StdMutex::new("one".to_owned()));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (password2, requests2) = (Arc::clone(&password), Arc::clone(&requests));
+        let url = serve(move |parts, body| {
+            requests2.fetch_add(1, Ordering::Relaxed);
+            let expected = format!(
+                "Basic {}",
+                base64::encode(format!("__cookie__:{}", password2.lock().unwrap()))
+            );
+            if parts.headers[header::AUTHORIZATION] != expected.as_str() {
+                let mut res = HttpResponse::new(Body::empty());
+                *res.status_mut() = HttpStatusCode::UNAUTHORIZED;
+                return res;
+            }
+            let request: serde_json::Value = serde_json::from_slice(body).unwrap();
+            let response = serde_json::json!({ "result": [], "error": null, "id": request["id"] });
+            HttpResponse::new(Body::from(response.to_string()))
+        });
+
+        let auth = RPCAuth::cookie(path.to_str().unwrap());
+        let semaphore = Arc::new(Semaphore::new(1));
+        let client = RPCClient::new(url.parse().unwrap(), auth, http_config(), semaphore).unwrap();
+        client.get_raw_mempool().await.unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Node restarted with new cookie, request repeated after reload
+        fs::write(&path, "__cookie__:two").unwrap();
+        *password.lock().unwrap() = "two".to_owned();
+        client.get_raw_mempool().await.unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+        // Cookie not changed, request repeated only once
+        *password.lock().unwrap() = "three".to_owned();
+        let result = client.get_raw_mempool().await;
+        assert!(matches!(result, Err(BitcoindError::Unauthorized)));
+        assert_eq!(requests.load(Ordering::Relaxed), 5);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rpc_batch_results() {
+        let data = r#"[
+            {"result": null, "error": {"code": -8, "message": "Block height out of range"}, "id": 12},
+            {"result": 10, "error": null, "id": 10},
+            {"result": 11, "error": null, "id": 11}
+        ]"#;
+        let data = serde_json::from_str::<Vec<Response<u32>>>(data).unwrap();
+        let results = batch_results(&[10, 11, 12], data).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &10);
+        assert_eq!(results[1].as_ref().unwrap(), &11);
+        match results[2] {
+            Err(BitcoindError::ResultRPC(ref error)) => assert_eq!(error.code, -8),
+            ref result => panic!("Unexpected result: {:?}", result),
+        }
+
+        let data =
+            r#"[{"result": 10, "error": null, "id": 10}, {"result": 10, "error": null, "id": 10}]"#;
+        let data = serde_json::from_str::<Vec<Response<u32>>>(data).unwrap();
+        assert!(matches!(
+            batch_results(&[10, 11], data),
+            Err(BitcoindError::NonceMismatch)
+        ));
+    }
+}
@@ -0,0 +1,296 @@
+// Deserialize JSON while response body is received, instead of buffer whole
+// body. Verbose block can be few times bigger than raw block and with
+// `sync_threads + 2` blocks in flight it's a lot of memory.
+//
+// serde_json have only blocking `from_reader`, so big responses deserialized
+// in blocking thread. Chunks are received in async task and passed to parser
+// through bounded channel, so body is not received faster than parsed.
+
+use std::io::{self, Read};
+use std::sync::{mpsc, Arc};
+
+use futures::future::{self, Either};
+use reqwest::Response;
+use serde::de::DeserializeOwned;
+use tokio::sync::Semaphore;
+
+use super::error::{BitcoindError, BitcoindResult};
+use super::http::{HttpConfig, Timeout};
+
+// Small responses are parsed from buffer, without blocking thread
+static STREAM_MIN_SIZE: u64 = 64 * 1024;
+// Received but not parsed chunks
+static STREAM_CHUNKS: usize = 16;
+
+// Reader over chunks, `next_chunk` put next chunk to buffer and return
+// `false` if there are no more chunks
+pub struct ChunksReader<F> {
+    next_chunk: F,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<F> ChunksReader<F>
+where
+    F: FnMut(&mut Vec<u8>) -> io::Result<bool>,
+{
+    pub fn new(next_chunk: F) -> ChunksReader<F> {
+        ChunksReader {
+            next_chunk,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl<F> Read for ChunksReader<F>
+where
+    F: FnMut(&mut Vec<u8>) -> io::Result<bool>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            self.chunk.clear();
+            self.pos = 0;
+            if !(self.next_chunk)(&mut self.chunk)? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+pub async fn from_response<T>(
+    res: Response,
+    http: HttpConfig,
+    timeout: Timeout,
+) -> BitcoindResult<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    if res.content_length().unwrap_or(u64::MAX) < STREAM_MIN_SIZE {
+        let body_fut = res.bytes();
+        let body = body_fut.await.map_err(|e| http.map_error(e, timeout))?;
+        return serde_json::from_slice(&body).map_err(BitcoindError::ResponseParse);
+    }
+
+    let (tx, rx) = mpsc::sync_channel(STREAM_CHUNKS);
+    // Free slots in channel, chunk is received only when there is free slot,
+    // so `send` never block async thread
+    let slots = Arc::new(Semaphore::new(STREAM_CHUNKS));
+
+    let receiver_slots = Arc::clone(&slots);
+    let receiver = async move {
+        let mut res = res;
+        loop {
+            receiver_slots.acquire().await.forget();
+            let (chunk, last) = match res.chunk().await {
+                Ok(Some(data)) => (Ok(data), false),
+                Ok(None) => break,
+                Err(e) => (Err(e), true),
+            };
+            // Parser already finished, on invalid JSON
+            if tx.send(chunk).is_err() || last {
+                break;
+            }
+        }
+    };
+
+    let parser = tokio::task::spawn_blocking(move || {
+        // Keep original error, serde_json wrap it to own error
+        let mut error = None;
+
+        let reader = ChunksReader::new(|chunk: &mut Vec<u8>| match rx.recv() {
+            Ok(Ok(data)) => {
+                slots.add_permits(1);
+                chunk.extend_from_slice(&data);
+                Ok(true)
+            }
+            Ok(Err(e)) => {
+                error = Some(e);
+                Err(io::ErrorKind::Other.into())
+            }
+            // Sender dropped after last chunk
+            Err(_) => Ok(false),
+        });
+        let result = serde_json::from_reader(reader);
+
+        match (result, error) {
+            (Ok(value), _) => Ok(value),
+            (Err(_), Some(e)) => Err(http.map_error(e, timeout)),
+            (Err(e), None) => Err(BitcoindError::ResponseParse(e)),
+        }
+    });
+
+    // Parser can finish before whole body received, receiver is dropped then
+    let result = match future::select(Box::pin(receiver), parser).await {
+        Either::Left(((), parser)) => parser.await,
+        Either::Right((result, _)) => result,
+    };
+    result.map_err(BitcoindError::TaskJoin)?
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use hyper::{Body, Response as HttpResponse};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::bitcoin::bitcoind::tests::{http_config, serve};
+
+    // Body in chunks of 1000 bytes, without Content-Length
+    fn chunked(data: &[u8], error: bool) -> HttpResponse<Body> {
+        let mut chunks = data
+            .chunks(1000)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect::<Vec<io::Result<Vec<u8>>>>();
+        if error {
+            chunks.push(Err(io::ErrorKind::Other.into()));
+        }
+        HttpResponse::new(Body::wrap_stream(stream::iter(chunks)))
+    }
+
+    async fn get(url: &str) -> BitcoindResult<Value> {
+        let http = http_config();
+        let client = http.build_client().unwrap();
+        let res = client.get(url).send().await.unwrap();
+        from_response(res, http, http.request()).await
+    }
+
+    #[tokio::test]
+    async fn stream_from_response() {
+        let value = json!({ "values": (0..100_000).collect::<Vec<u32>>() });
+        let data = serde_json::to_vec(&value).unwrap();
+
+        // Small body with Content-Length parsed from buffer
+        let url = serve(|_, _| HttpResponse::new(Body::from(r#"{"value":1}"#)));
+        assert_eq!(get(&url).await.unwrap(), json!({ "value": 1 }));
+
+        // Chunked body parsed in blocking thread
+        let body = data.clone();
+        let url = serve(move |_, _| chunked(&body, false));
+        assert_eq!(get(&url).await.unwrap(), value);
+
+        // Truncated JSON
+        let body = data[..data.len() / 2].to_vec();
+        let url = serve(move |_, _| chunked(&body, false));
+        match get(&url).await {
+            Err(BitcoindError::ResponseParse(_)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        // Invalid JSON in first chunk, rest of body is not received
+        let mut body = b"[}".to_vec();
+        body.extend_from_slice(&data);
+        let url = serve(move |_, _| chunked(&body, false));
+        match get(&url).await {
+            Err(BitcoindError::ResponseParse(_)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        // Connection closed in the middle of body, original error is kept
+        let body = data[..data.len() / 2].to_vec();
+        let url = serve(move |_, _| chunked(&body, true));
+        match get(&url).await {
+            Err(BitcoindError::Reqwest(_)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    // Allocator replaced for whole test binary and counters are shared by
+    // all threads, so only with feature and without other tests:
+    // `cargo test --features test-peak-memory peak_memory`
+    #[cfg(feature = "test-peak-memory")]
+    mod peak_memory {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::sync::atomic::{AtomicIsize, Ordering};
+        use std::time::Duration;
+
+        use super::*;
+        use crate::bitcoin::bitcoind::json::Block;
+
+        struct Allocator;
+
+        static ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+        static ALLOCATED_PEAK: AtomicIsize = AtomicIsize::new(0);
+
+        unsafe impl GlobalAlloc for Allocator {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let ptr = System.alloc(layout);
+                if !ptr.is_null() {
+                    let size = layout.size() as isize;
+                    let value = ALLOCATED.fetch_add(size, Ordering::SeqCst) + size;
+                    ALLOCATED_PEAK.fetch_max(value, Ordering::SeqCst);
+                }
+                ptr
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout);
+                ALLOCATED.fetch_sub(layout.size() as isize, Ordering::SeqCst);
+            }
+        }
+
+        #[global_allocator]
+        static ALLOCATOR: Allocator = Allocator;
+
+        // Verbose block with `count` transactions of 4000 bytes
+        fn fixture_block(count: usize) -> String {
+            let hash = "00".repeat(32);
+            let tx = format!(
+                r#"{{"txid":"{hash}","hash":"{hash}","version":1,"size":4000,"hex":"{hex}","vin":[{{"txid":"{hash}","vout":0,"scriptSig":{{"asm":"","hex":""}},"txinwitness":["{hash}"],"sequence":4294967295}}],"vout":[{{"value":0.00010000,"n":0,"scriptPubKey":{{"asm":"","hex":"{script}","type":"nonstandard"}}}}]}}"#,
+                hash = hash,
+                hex = "ab".repeat(4000),
+                script = "cd".repeat(25),
+            );
+            let txs = vec![tx; count].join(",");
+            format!(
+                r#"{{"hash":"{}","height":1,"size":{},"time":0,"tx":[{}]}}"#,
+                hash,
+                count * 4000,
+                txs
+            )
+        }
+
+        #[tokio::test]
+        async fn stream_block_peak_memory() {
+            // Served chunks are not copied, so not counted as received
+            let data: &'static [u8] = Box::leak(fixture_block(500).into_boxed_str()).as_bytes();
+            let len = data.len();
+            let url = serve(move |_, _| {
+                let chunks = data.chunks(16 * 1024).map(Ok::<_, io::Error>);
+                HttpResponse::new(Body::wrap_stream(stream::iter(chunks)))
+            });
+
+            let http = HttpConfig {
+                request_timeout: Duration::from_secs(30),
+                ..http_config()
+            };
+            let client = http.build_client().unwrap();
+
+            let start = ALLOCATED.load(Ordering::SeqCst);
+            ALLOCATED_PEAK.store(start, Ordering::SeqCst);
+
+            let res = client.get(&url).send().await.unwrap();
+            let block: Block = from_response(res, http, http.request()).await.unwrap();
+            assert_eq!(block.transactions.len(), 500);
+            assert_eq!(block.transactions[0].hex.len(), 4000);
+            let parsed = (ALLOCATED.load(Ordering::SeqCst) - start) as usize;
+            drop(block);
+
+            // Body is not buffered, so in memory parsed block and few chunks only
+            let peak = (ALLOCATED_PEAK.load(Ordering::SeqCst) - start) as usize;
+            assert!(
+                peak - parsed < len / 4,
+                "Peak memory {} (parsed block {}) for {} bytes of JSON",
+                peak,
+                parsed,
+                len
+            );
+        }
+    }
+}
@@ -0,0 +1,290 @@
+// Notifications from bitcoind through ZMQ (`-zmqpubhashblock`, `-zmqpubrawtx`,
+// `-zmqpubsequence`). See https://github.com/bitcoin/bitcoin/blob/master/doc/zmq.md
+//
+// `zmq` socket is blocking, so messages received in separate thread and
+// passed to async code through channel.
+
+use std::convert::TryInto as _;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use tokio::sync::mpsc;
+
+use super::error::{BitcoindError, BitcoindResult};
+use crate::fixed_hash::H256;
+
+static ZMQ_TOPICS: &[&str] = &["hashblock", "rawtx", "sequence"];
+
+// Timeout for `recv`, so thread can check that subscription was dropped
+static ZMQ_RECV_TIMEOUT_MS: i32 = 1_000;
+
+#[derive(Debug, PartialEq)]
+pub enum ZMQMessage {
+    HashBlock(H256),
+    RawTx(Vec<u8>),
+    Sequence(H256, ZMQSequenceEvent),
+    // Some messages were dropped, state should be fetched through RPC
+    Missed,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ZMQSequenceEvent {
+    BlockConnected,
+    BlockDisconnected,
+    TransactionAdded(u64),
+    TransactionRemoved(u64),
+}
+
+impl ZMQMessage {
+    // New block or chain tip changed
+    pub fn is_block(&self) -> bool {
+        match self {
+            ZMQMessage::HashBlock(_) | ZMQMessage::Missed => true,
+            ZMQMessage::Sequence(_, event) => matches!(
+                event,
+                ZMQSequenceEvent::BlockConnected | ZMQSequenceEvent::BlockDisconnected
+            ),
+            ZMQMessage::RawTx(_) => false,
+        }
+    }
+
+    // Parse multipart message: topic, body, sequence number (LE u32)
+    fn from_parts(parts: &[Vec<u8>]) -> BitcoindResult<(&str, u32, ZMQMessage)> {
+        let invalid = || {
+            let topic = parts.first().map(|topic| String::from_utf8_lossy(topic));
+            BitcoindError::ZMQInvalidMessage(topic.unwrap_or_default().into_owned())
+        };
+
+        if parts.len() != 3 || parts[2].len() != 4 {
+            return Err(invalid());
+        }
+        let seq = u32::from_le_bytes(parts[2].as_slice().try_into().unwrap());
+
+        let body = &parts[1];
+        let (topic, msg) = match parts[0].as_slice() {
+            b"hashblock" if body.len() == 32 => {
+                ("hashblock", ZMQMessage::HashBlock(H256::from_slice(body)))
+            }
+            b"rawtx" => ("rawtx", ZMQMessage::RawTx(body.clone())),
+            b"sequence" if body.len() >= 33 => {
+                let hash = H256::from_slice(&body[0..32]);
+                let mempool_seq = || match body[33..].try_into() {
+                    Ok(bytes) => Ok(u64::from_le_bytes(bytes)),
+                    Err(_) => Err(invalid()),
+                };
+                let event = match (body[32], body.len()) {
+                    (b'C', 33) => ZMQSequenceEvent::BlockConnected,
+                    (b'D', 33) => ZMQSequenceEvent::BlockDisconnected,
+                    (b'A', _) => ZMQSequenceEvent::TransactionAdded(mempool_seq()?),
+                    (b'R', _) => ZMQSequenceEvent::TransactionRemoved(mempool_seq()?),
+                    _ => return Err(invalid()),
+                };
+                ("sequence", ZMQMessage::Sequence(hash, event))
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok((topic, seq, msg))
+    }
+}
+
+pub struct ZMQClient {
+    endpoint: String,
+}
+
+impl fmt::Debug for ZMQClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZMQClient")
+            .field("endpoint", &self.endpoint)
+            .finish()
+    }
+}
+
+impl ZMQClient {
+    pub fn new(endpoint: String) -> ZMQClient {
+        ZMQClient { endpoint }
+    }
+
+    // Connect to publisher and start receive messages in separate thread
+    pub fn subscribe(&self) -> BitcoindResult<ZMQSubscription> {
+        let ctx = zmq::Context::new();
+        let socket = ctx.socket(zmq::SUB).map_err(BitcoindError::ZMQ)?;
+        socket
+            .set_rcvtimeo(ZMQ_RECV_TIMEOUT_MS)
+            .map_err(BitcoindError::ZMQ)?;
+        for topic in ZMQ_TOPICS {
+            socket
+                .set_subscribe(topic.as_bytes())
+                .map_err(BitcoindError::ZMQ)?;
+        }
+        socket.connect(&self.endpoint).map_err(BitcoindError::ZMQ)?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped1 = Arc::clone(&stopped);
+        thread::Builder::new()
+            .name("zmq".to_owned())
+            .spawn(move || {
+                // Context should live as long as socket
+                let _ctx = ctx;
+                let mut seqs = [None; 3];
+
+                while !stopped1.load(Ordering::Relaxed) {
+                    let parts = match socket.recv_multipart(0) {
+                        Ok(parts) => parts,
+                        Err(zmq::Error::EAGAIN) => continue,
+                        Err(e) => {
+                            let _ = tx.send(Err(BitcoindError::ZMQ(e)));
+                            break;
+                        }
+                    };
+
+                    let result = ZMQMessage::from_parts(&parts).map(|(topic, seq, msg)| {
+                        // Every topic have own sequence number
+                        let index = ZMQ_TOPICS.iter().position(|t| *t == topic).unwrap();
+                        let missed =
+                            matches!(seqs[index], Some(prev) if seq != u32::wrapping_add(prev, 1));
+                        seqs[index] = Some(seq);
+                        (missed, msg)
+                    });
+
+                    let sent = match result {
+                        Ok((true, msg)) => tx
+                            .send(Ok(ZMQMessage::Missed))
+                            .and_then(|_| tx.send(Ok(msg))),
+                        Ok((false, msg)) => tx.send(Ok(msg)),
+                        Err(e) => tx.send(Err(e)),
+                    };
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Failed to spawn ZMQ thread");
+
+        Ok(ZMQSubscription { rx, stopped })
+    }
+}
+
+// Received messages, receive thread stopped on drop
+pub struct ZMQSubscription {
+    rx: mpsc::UnboundedReceiver<BitcoindResult<ZMQMessage>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for ZMQSubscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZMQSubscription").finish()
+    }
+}
+
+impl ZMQSubscription {
+    // Return `None` if receive thread exited
+    pub async fn recv(&mut self) -> Option<BitcoindResult<ZMQMessage>> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for ZMQSubscription {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stand-in for bitcoind publisher. XPUB socket receive subscriptions,
+    // so we know when subscriber is ready and no messages will be lost.
+    struct Publisher {
+        socket: zmq::Socket,
+        _ctx: zmq::Context,
+    }
+
+    impl Publisher {
+        fn new() -> (Publisher, String) {
+            let ctx = zmq::Context::new();
+            let socket = ctx.socket(zmq::XPUB).unwrap();
+            socket.bind("tcp://127.0.0.1:*").unwrap();
+            let endpoint = socket.get_last_endpoint().unwrap().unwrap();
+            (Publisher { socket, _ctx: ctx }, endpoint)
+        }
+
+        fn wait_subscriptions(&self) {
+            for _ in ZMQ_TOPICS {
+                let msg = self.socket.recv_bytes(0).unwrap();
+                assert_eq!(msg[0], 1, "expected subscribe message");
+            }
+        }
+
+        fn send(&self, topic: &str, body: &[u8], seq: u32) {
+            let seq = seq.to_le_bytes();
+            let parts = vec![topic.as_bytes(), body, &seq];
+            self.socket.send_multipart(parts, 0).unwrap();
+        }
+    }
+
+    async fn recv(sub: &mut ZMQSubscription) -> ZMQMessage {
+        sub.recv().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn zmq_receive_messages() {
+        let (publisher, endpoint) = Publisher::new();
+        let mut sub = ZMQClient::new(endpoint).subscribe().unwrap();
+        publisher.wait_subscriptions();
+
+        let hash = H256::repeat_byte(0x11);
+        publisher.send("hashblock", hash.as_bytes(), 0);
+        assert_eq!(recv(&mut sub).await, ZMQMessage::HashBlock(hash));
+
+        publisher.send("rawtx", &[1, 2, 3], 7);
+        assert_eq!(recv(&mut sub).await, ZMQMessage::RawTx(vec![1, 2, 3]));
+
+        let mut body = hash.as_bytes().to_vec();
+        body.push(b'C');
+        publisher.send("sequence", &body, 0);
+        let event = ZMQSequenceEvent::BlockConnected;
+        assert_eq!(recv(&mut sub).await, ZMQMessage::Sequence(hash, event));
+
+        let txid = H256::repeat_byte(0x22);
+        let mut body = txid.as_bytes().to_vec();
+        body.push(b'A');
+        body.extend_from_slice(&42u64.to_le_bytes());
+        publisher.send("sequence", &body, 1);
+        let event = ZMQSequenceEvent::TransactionAdded(42);
+        assert_eq!(recv(&mut sub).await, ZMQMessage::Sequence(txid, event));
+    }
+
+    #[tokio::test]
+    async fn zmq_missed_messages() {
+        let (publisher, endpoint) = Publisher::new();
+        let mut sub = ZMQClient::new(endpoint).subscribe().unwrap();
+        publisher.wait_subscriptions();
+
+        let hash = H256::repeat_byte(0x33);
+        publisher.send("hashblock", hash.as_bytes(), 10);
+        assert_eq!(recv(&mut sub).await, ZMQMessage::HashBlock(hash));
+
+        publisher.send("hashblock", hash.as_bytes(), 12);
+        assert_eq!(recv(&mut sub).await, ZMQMessage::Missed);
+        assert_eq!(recv(&mut sub).await, ZMQMessage::HashBlock(hash));
+        assert!(ZMQMessage::Missed.is_block());
+    }
+
+    #[tokio::test]
+    async fn zmq_invalid_message() {
+        let (publisher, endpoint) = Publisher::new();
+        let mut sub = ZMQClient::new(endpoint).subscribe().unwrap();
+        publisher.wait_subscriptions();
+
+        publisher.send("hashblock", &[0; 31], 0);
+        match sub.recv().await.unwrap() {
+            Err(BitcoindError::ZMQInvalidMessage(topic)) => assert_eq!(topic, "hashblock"),
+            v => panic!("unexpected result: {:?}", v),
+        }
+    }
+}
@@ -1,18 +1,78 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use super::bitcoind::json::Block;
+use bytes::{BufMut, BytesMut};
+use futures::{pin_mut, SinkExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio_postgres::types::{IsNull, ToSql, Type};
+use tokio_postgres::{Row, Transaction};
+
+use super::amount::{Amount, AmountSum};
+use super::bitcoind::json::{Block, Transaction as BitcoindTransaction, TransactionInput};
 use crate::db::{DataBase, StaticQueries};
+use crate::error::CustomError;
 use crate::fixed_hash::H256;
 use crate::shutdown::Shutdown;
 use crate::{AnyResult, EmptyResult};
 
-static DATABASE_VERSION: u16 = 1;
+static DATABASE_VERSION: u16 = 3;
 static DATABASE_QUERIES: StaticQueries = &[
     ("create", include_str!("./sql/create.sql")),
     ("transform", include_str!("./sql/transform.sql")),
     ("indexer", include_str!("./sql/indexer.sql")),
 ];
 
+// Column types for binary `COPY` on initial sync
+static TRANSACTIONS_INITIAL_COPY_TYPES: &[Type] = &[
+    Type::INT4,
+    Type::INT4,
+    Type::BYTEA,
+    Type::BYTEA,
+    Type::TIMESTAMP,
+    Type::INT4,
+    Type::INT4,
+    Type::INT8,
+];
+static TRANSACTIONS_INPUTS_COPY_TYPES: &[Type] = &[
+    Type::INT4,
+    Type::BYTEA,
+    Type::INT4,
+    Type::TEXT,
+    Type::BYTEA,
+    Type::INT4,
+];
+static TRANSACTIONS_OUTPUTS_COPY_TYPES: &[Type] =
+    &[Type::INT4, Type::BYTEA, Type::INT4, Type::TEXT];
+
+// Data in `COPY` sent to server by chunks of this size
+static COPY_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+// Column types for `{VALUES}` in multi-row inserts
+static TRANSACTIONS_INPUTS_TYPES: &[&str] = &["int4", "bytea", "int4", "text", "bytea", "int4"];
+static TRANSACTIONS_INPUTS_COINBASE_TYPES: &[&str] = &["int4", "bytea", "int4", "text"];
+static TRANSACTIONS_OUTPUTS_TYPES: &[&str] = &["int4", "bytea", "int4", "text"];
+static TRANSACTIONS_TYPES: &[&str] = &[
+    "int4",
+    "int4",
+    "bytea",
+    "bytea",
+    "timestamp",
+    "int4",
+    "int8",
+    "int4",
+    "int8",
+];
+static STATS_ADDRESSES_TYPES: &[&str] = &["text", "int4", "int4", "numeric", "numeric"];
+static ADDRESS_HISTORY_TYPES: &[&str] =
+    &["text", "int4", "bytea", "int4", "timestamp", "int8", "int8"];
+static ADDRESS_UNSPENT_TYPES: &[&str] = &["text", "int4", "bytea", "int4", "int8"];
+
+// Queries from `transform` group which used by steps, but not steps itself
+static TRANSFORM_NOT_STEPS: &[&str] = &["blocksProcessed"];
+
 crate::db_add_basic_methods!(IndexerDataBase);
 
 // Move macros to databse: add_from_args, add_shared_methods
@@ -42,14 +102,1201 @@ impl IndexerDataBase {
         let client = self.db.pool.get().await?;
         let row = client.query_opt(query, &[]).await?;
         Ok(row.map(|row| {
-            let height: u32 = row.get("height");
+            let height: i32 = row.get("height");
             let hash: Vec<u8> = row.get("hash");
-            (height, H256::from_slice(&hash))
+            (height as u32, H256::from_slice(&hash))
         }))
     }
 
-    pub async fn push_block(&self, block: &Block) -> AnyResult<()> {
-        println!("Push block: {} => {}", block.height, block.hash);
+    // Size of best block, next blocks usually have similar size
+    pub async fn get_bestblock_size(&self) -> AnyResult<Option<u32>> {
+        let query = self.db.queries.get("indexer", "blocksSelectBestSize");
+        let client = self.db.pool.get().await?;
+        let row = client.query_opt(query, &[]).await?;
+        Ok(row.map(|row| {
+            let size: i32 = row.get("size");
+            size as u32
+        }))
+    }
+
+    // Return hash of block at specified height
+    pub async fn get_block_hash(&self, height: u32) -> AnyResult<Option<H256>> {
+        let query = self.db.queries.get("indexer", "blocksSelectHashByHeight");
+        let client = self.db.pool.get().await?;
+        let row = client.query_opt(query, &[&(height as i32)]).await?;
+        Ok(row.map(|row| H256::from_slice(row.get("hash"))))
+    }
+
+    // Remove block with all related data on reorg. Outputs spent in this
+    // block returned to unspent, address stats updated in same transaction.
+    // Unconfirmed transactions which spent outputs from block removed too.
+    pub async fn remove_block(&self, height: u32) -> EmptyResult {
+        let queries = &self.db.queries["indexer"];
+        let height = height as i32;
+
+        let mut client = self.db.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let rows = tx
+            .query(&queries["transactionsSelectTxIdsByHeight"], &[&height])
+            .await?;
+        let txids = rows
+            .iter()
+            .map(|row| row.get("txid"))
+            .collect::<Vec<&[u8]>>();
+        self.remove_unconfirmed_with_descendants(&tx, &txids)
+            .await?;
+
+        let rows = tx
+            .query(&queries["statsAddressesRollbackHistory"], &[&height])
+            .await?;
+        let addresses = rows
+            .iter()
+            .map(|row| row.get("address"))
+            .collect::<Vec<String>>();
+
+        for name in &[
+            "statsAddressesRollbackUnspent",
+            "addressUnspentRestoreSpent",
+            "transactionsInputsOutputsRollbackOutputs",
+            "transactionsInputsOutputsRollbackCoinbase",
+            "transactionsInputsOutputsRollbackInputs",
+            "blocksDeleteByHeight",
+        ] {
+            tx.execute(&queries[name], &[&height]).await?;
+        }
+
+        tx.execute(&queries["statsAddressesDeleteEmpty"], &[&addresses])
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Insert block, transactions, inputs and outputs on initial sync.
+    // Everything inserted in one transaction, so block can not be saved partially.
+    // Rows written with binary `COPY`, which is much faster than `INSERT`.
+    pub async fn push_block(&self, block: &Block) -> EmptyResult {
+        let rows = BlockRows::from_block(block)?;
+
+        let queries = &self.db.queries["indexer"];
+        let mut client = self.db.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        rows.insert_block(&tx, &queries["blocksInsertOne"], block, None)
+            .await?;
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(rows.transactions.len() * 8);
+        for row in rows.transactions.iter() {
+            params.extend_from_slice(&[
+                &rows.height,
+                &row.index,
+                &row.txid,
+                &row.raw,
+                &rows.time,
+                &row.inputs_count,
+                &row.outputs_count,
+                &row.outputs_total,
+            ]);
+        }
+        let query = &queries["transactionsInitialCopy"];
+        copy_many(&tx, query, TRANSACTIONS_INITIAL_COPY_TYPES, &params).await?;
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(rows.inputs.len() * 6);
+        for row in rows.inputs.iter() {
+            params.extend_from_slice(&[
+                &rows.height,
+                &row.txid,
+                &row.vin,
+                &row.data,
+                &row.output_txid,
+                &row.output_vout,
+            ]);
+        }
+        let query = &queries["transactionsInitialCopyInputs"];
+        copy_many(&tx, query, TRANSACTIONS_INPUTS_COPY_TYPES, &params).await?;
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(rows.outputs.len() * 4);
+        for row in rows.outputs.iter() {
+            params.extend_from_slice(&[&rows.height, &row.txid, &row.vout, &row.data]);
+        }
+        let query = &queries["transactionsInitialCopyOutputs"];
+        copy_many(&tx, query, TRANSACTIONS_OUTPUTS_COPY_TYPES, &params).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Add block on top of the chain after initial sync. Unlike `push_block`,
+    // data inserted directly to final tables, so block should be connected
+    // to our best block. Unconfirmed transactions from block, conflicting with
+    // block and all their descendants are removed first.
+    pub async fn apply_block(&self, block: &Block) -> EmptyResult {
+        let rows = BlockRows::from_block(block)?;
+
+        let mut client = self.db.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let mut txids = rows
+            .transactions
+            .iter()
+            .map(|row| row.txid)
+            .collect::<Vec<_>>();
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![];
+        for row in rows.inputs.iter().filter(|row| row.output_txid.is_some()) {
+            params.extend_from_slice(&[&row.output_txid, &row.output_vout]);
+        }
+        let query =
+            &self.db.queries["indexer"]["transactionsInputsOutputsSelectUnconfirmedSpenders"];
+        let spenders = query_many(&tx, query, &["bytea", "int4"], &params).await?;
+        txids.extend(spenders.iter().map(|row| row.get::<_, &[u8]>("input_txid")));
+        self.remove_unconfirmed_with_descendants(&tx, &txids)
+            .await?;
+
+        if !self.insert_transactions(&tx, &rows, Some(block)).await? {
+            let msg = format!("Not all spent outputs found for block: {}", block.height);
+            return Err(CustomError::new_any(msg));
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Return txids of all unconfirmed transactions
+    pub async fn get_unconfirmed_txids(&self) -> AnyResult<Vec<H256>> {
+        let query = self
+            .db
+            .queries
+            .get("indexer", "transactionsSelectUnconfirmed");
+        let client = self.db.pool.get().await?;
+        let rows = client.query(query, &[]).await?;
+        Ok(rows
+            .iter()
+            .map(|row| H256::from_slice(row.get("txid")))
+            .collect())
+    }
+
+    // Add unconfirmed transactions, transactions can spend outputs of each other.
+    // Return `false` if not all spent outputs found, nothing added in this case.
+    pub async fn add_unconfirmed(&self, transactions: &[BitcoindTransaction]) -> AnyResult<bool> {
+        let rows = BlockRows::new(None, SystemTime::now(), transactions)?;
+
+        let mut client = self.db.pool.get().await?;
+        let tx = client.transaction().await?;
+        if !self.insert_transactions(&tx, &rows, None).await? {
+            return Ok(false);
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // Remove unconfirmed transactions (evicted or replaced) with descendants.
+    // Return number of removed transactions.
+    pub async fn remove_unconfirmed(&self, txids: &[H256]) -> AnyResult<usize> {
+        let txids = txids.iter().map(|txid| txid.as_bytes()).collect::<Vec<_>>();
+
+        let mut client = self.db.pool.get().await?;
+        let tx = client.transaction().await?;
+        let count = self
+            .remove_unconfirmed_with_descendants(&tx, &txids)
+            .await?;
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    // Remove unconfirmed transactions from `txids` and all unconfirmed
+    // descendants. Outputs spent by removed transactions returned to unspent.
+    async fn remove_unconfirmed_with_descendants(
+        &self,
+        tx: &Transaction<'_>,
+        txids: &[&[u8]],
+    ) -> AnyResult<usize> {
+        let queries = &self.db.queries["indexer"];
+
+        let rows = tx
+            .query(
+                &queries["transactionsSelectUnconfirmedDescendants"],
+                &[&txids],
+            )
+            .await?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        let txids = rows
+            .iter()
+            .map(|row| row.get("txid"))
+            .collect::<Vec<&[u8]>>();
+
+        let rows = tx
+            .query(
+                &queries["statsAddressesRemoveUnconfirmedHistory"],
+                &[&txids],
+            )
+            .await?;
+        let addresses = rows
+            .iter()
+            .map(|row| row.get("address"))
+            .collect::<Vec<String>>();
+
+        for name in &[
+            "statsAddressesRemoveUnconfirmedUnspent",
+            "addressUnspentRestoreUnconfirmedSpent",
+            "transactionsInputsOutputsRemoveUnconfirmedOutputs",
+            "transactionsInputsOutputsRemoveUnconfirmedInputs",
+            "transactionsDeleteUnconfirmed",
+        ] {
+            tx.execute(&queries[name], &[&txids]).await?;
+        }
+
+        tx.execute(&queries["statsAddressesDeleteEmpty"], &[&addresses])
+            .await?;
+
+        Ok(txids.len())
+    }
+
+    // Insert transactions to final tables, with block if specified.
+    // Return `false` if not all spent outputs found, transaction should be
+    // rolled back in this case.
+    async fn insert_transactions(
+        &self,
+        tx: &Transaction<'_>,
+        rows: &BlockRows<'_>,
+        block: Option<&Block>,
+    ) -> AnyResult<bool> {
+        let queries = &self.db.queries["indexer"];
+
+        // Add outputs first, because they can be spent in the same block
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(rows.outputs.len() * 4);
+        for row in rows.outputs.iter() {
+            params.extend_from_slice(&[&rows.height, &row.txid, &row.vout, &row.data]);
+        }
+        let query = &queries["transactionsInputsOutputsInsertOutputs"];
+        query_many(tx, query, TRANSACTIONS_OUTPUTS_TYPES, &params).await?;
+
+        // Coinbase inputs do not spent anything
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![];
+        for row in rows.inputs.iter().filter(|row| row.output_txid.is_none()) {
+            params.extend_from_slice(&[&rows.height, &row.txid, &row.vin, &row.data]);
+        }
+        let query = &queries["transactionsInputsOutputsInsertCoinbase"];
+        query_many(tx, query, TRANSACTIONS_INPUTS_COINBASE_TYPES, &params).await?;
+
+        // Link inputs to spent outputs, spent outputs data returned
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![];
+        for row in rows.inputs.iter().filter(|row| row.output_txid.is_some()) {
+            params.extend_from_slice(&[
+                &rows.height,
+                &row.txid,
+                &row.vin,
+                &row.data,
+                &row.output_txid,
+                &row.output_vout,
+            ]);
+        }
+        let query = &queries["transactionsInputsOutputsUpdateInputs"];
+        let spent_rows = query_many(tx, query, TRANSACTIONS_INPUTS_TYPES, &params).await?;
+        if spent_rows.len() * TRANSACTIONS_INPUTS_TYPES.len() != params.len() {
+            return Ok(false);
+        }
+
+        let mut spent = HashMap::with_capacity(spent_rows.len());
+        for row in spent_rows.iter() {
+            let txid: Vec<u8> = row.get("txid");
+            let vin: i32 = row.get("vin");
+            let data: OutputData = serde_json::from_str(row.get("output_data"))?;
+            spent.insert((txid, vin), data);
+        }
+
+        let changes = BlockChanges::new(rows, &spent)?;
+
+        if let Some(block) = block {
+            let query = &queries["blocksInsertOne"];
+            let inputs_total = Some(changes.inputs_total);
+            rows.insert_block(tx, query, block, inputs_total).await?;
+        }
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(rows.transactions.len() * 9);
+        for (row, inputs_total) in rows.transactions.iter().zip(changes.tx_inputs_total.iter()) {
+            params.extend_from_slice(&[
+                &rows.height,
+                &row.index,
+                &row.txid,
+                &row.raw,
+                &rows.time,
+                &row.inputs_count,
+                inputs_total,
+                &row.outputs_count,
+                &row.outputs_total,
+            ]);
+        }
+        let query = &queries["transactionsInsertMany"];
+        query_many(tx, query, TRANSACTIONS_TYPES, &params).await?;
+
+        // Stats should be inserted before history and unspent, because of fkeys
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(changes.stats.len() * 5);
+        for (address, stats) in changes.stats.iter() {
+            params.extend_from_slice(&[
+                address,
+                &stats.count_history,
+                &stats.count_unspent,
+                &stats.received,
+                &stats.sent,
+            ]);
+        }
+        let query = match block {
+            Some(_) => &queries["statsAddressesUpsertMany"],
+            None => &queries["statsAddressesUpsertManyUnconfirmed"],
+        };
+        query_many(tx, query, STATS_ADDRESSES_TYPES, &params).await?;
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(changes.history.len() * 7);
+        for row in changes.history.iter() {
+            params.extend_from_slice(&[
+                &row.address,
+                &rows.height,
+                &row.txid,
+                &row.tx_index,
+                &rows.time,
+                &row.received,
+                &row.sent,
+            ]);
+        }
+        let query = &queries["addressHistoryInsertMany"];
+        query_many(tx, query, ADDRESS_HISTORY_TYPES, &params).await?;
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(changes.spent.len() * 2);
+        for (txid, vout) in changes.spent.iter() {
+            params.extend_from_slice(&[txid, vout]);
+        }
+        let query = &queries["addressUnspentDeleteMany"];
+        query_many(tx, query, &["bytea", "int4"], &params).await?;
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(changes.unspent.len() * 5);
+        for row in changes.unspent.iter() {
+            params.extend_from_slice(&[
+                &row.address,
+                &rows.height,
+                &row.txid,
+                &row.vout,
+                &row.value,
+            ]);
+        }
+        let query = &queries["addressUnspentInsertMany"];
+        query_many(tx, query, ADDRESS_UNSPENT_TYPES, &params).await?;
+
+        Ok(true)
+    }
+
+    // Return transform steps which are not executed yet. Steps are queries
+    // from `transform` group in the same order as in file.
+    pub async fn get_transform_steps(&self) -> Vec<&str> {
+        let stage = self.db.get_stage().await.0;
+        let steps = self.db.queries["transform"]
+            .iter()
+            .map(|(name, _query)| name)
+            .filter(|name| !TRANSFORM_NOT_STEPS.contains(name));
+
+        if stage == "#created" {
+            steps.collect()
+        } else if let Some(last_step) = stage.strip_prefix("#transform:") {
+            steps
+                .skip_while(|name| *name != last_step)
+                .skip(1)
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    // Execute transform step and save it as current stage. For per-block
+    // transform only stage saved, blocks should be transformed before.
+    pub async fn execute_transform_step(&self, name: &str) -> EmptyResult {
+        let queries = match name {
+            "blocksTransformFnCall" => vec![],
+            _ => vec![&self.db.queries["transform"][name]],
+        };
+        let stage = format!("#transform:{}", name);
+        self.db.execute_with_stage(&queries, &stage).await
+    }
+
+    // Return total number of blocks and heights of blocks which are not transformed yet
+    pub async fn get_transform_block_heights(&self) -> AnyResult<(usize, Vec<u32>)> {
+        let query = self.db.queries.get("transform", "blocksProcessed");
+        let client = self.db.pool.get().await?;
+        let rows = client.query(query, &[]).await?;
+
+        let mut heights = rows
+            .iter()
+            .filter(|row| !row.get::<_, bool>("processed"))
+            .map(|row| row.get::<_, i32>("height") as u32)
+            .collect::<Vec<u32>>();
+        heights.sort();
+        Ok((rows.len(), heights))
+    }
+
+    // Transform block from initial tables, return number of executed queries
+    pub async fn transform_block(&self, height: u32) -> AnyResult<u32> {
+        let query = self.db.queries.get("transform", "blocksTransformFnCall");
+        let client = self.db.pool.get().await?;
+        let row = client.query_one(query, &[&(height as i32)]).await?;
+        Ok(row.get::<_, i32>("count") as u32)
+    }
+}
+
+// Execute query with `{VALUES}` template. Each row in `{VALUES}` build from
+// `types`, where every item is type of column. Rows are splitted to chunks,
+// because PostgreSQL support only 65535 parameters in one query.
+async fn query_many(
+    tx: &Transaction<'_>,
+    query: &str,
+    types: &[&str],
+    params: &[&(dyn ToSql + Sync)],
+) -> AnyResult<Vec<Row>> {
+    let mut rows = vec![];
+
+    let chunk_size = (u16::MAX as usize / types.len()) * types.len();
+    for chunk in params.chunks(chunk_size) {
+        let mut values = String::new();
+        for (idx, _) in chunk.iter().enumerate() {
+            let col = idx % types.len();
+            if col == 0 {
+                values += if idx == 0 { "(" } else { "), (" };
+            } else {
+                values += ", ";
+            }
+            write!(values, "${}::{}", idx + 1, types[col]).unwrap();
+        }
+        values += ")";
+
+        let query = query.replace("{VALUES}", &values);
+        rows.extend(tx.query(query.as_str(), chunk).await?);
+    }
+
+    Ok(rows)
+}
+
+// Execute `COPY ... FROM STDIN BINARY` query, `params` are rows with columns
+// of `types`. Data encoded to buffer and sent to server when buffer size
+// reach `COPY_CHUNK_SIZE`, so memory usage is bounded for huge blocks.
+// See https://www.postgresql.org/docs/current/sql-copy.html (Binary Format)
+async fn copy_many(
+    tx: &Transaction<'_>,
+    query: &str,
+    types: &[Type],
+    params: &[&(dyn ToSql + Sync)],
+) -> AnyResult<u64> {
+    let sink = tx.copy_in(query, &[]).await?;
+    pin_mut!(sink);
+
+    let mut buf = BytesMut::with_capacity(COPY_CHUNK_SIZE);
+    buf.put_slice(b"PGCOPY\n\xff\r\n\0");
+    buf.put_i32(0); // flags
+    buf.put_i32(0); // header extension length
+
+    for row in params.chunks(types.len()) {
+        buf.put_i16(types.len() as i16);
+        for (value, ty) in row.iter().zip(types.iter()) {
+            // Reserve place for length, because it's known only after encoding
+            let pos = buf.len();
+            buf.put_i32(0);
+            let len = match value.to_sql_checked(ty, &mut buf)? {
+                IsNull::Yes => -1,
+                IsNull::No => (buf.len() - pos - 4) as i32,
+            };
+            buf[pos..pos + 4].copy_from_slice(&len.to_be_bytes());
+        }
+
+        if buf.len() >= COPY_CHUNK_SIZE {
+            sink.send(buf.split().freeze()).await?;
+        }
+    }
+
+    buf.put_i16(-1); // trailer
+    sink.send(buf.freeze()).await?;
+
+    Ok(sink.finish().await?)
+}
+
+// Data of output in `transactions_outputs` and `transactions_inputs_outputs`
+#[derive(Debug, Serialize, Deserialize)]
+struct OutputData {
+    value: Amount,
+    addresses: Vec<String>,
+}
+
+// Block (or unconfirmed transactions, without height) data in format for
+// inserting to database
+struct BlockRows<'a> {
+    height: Option<i32>,
+    time: SystemTime,
+    outputs_total: AmountSum,
+    transactions: Vec<TransactionRow<'a>>,
+    inputs: Vec<InputRow<'a>>,
+    outputs: Vec<OutputRow<'a>>,
+}
+
+struct TransactionRow<'a> {
+    index: Option<i32>,
+    txid: &'a [u8],
+    raw: &'a [u8],
+    inputs_count: i32,
+    outputs_count: i32,
+    outputs_total: Amount,
+}
+
+struct InputRow<'a> {
+    tx_index: usize,
+    txid: &'a [u8],
+    vin: i32,
+    data: String,
+    output_txid: Option<&'a [u8]>,
+    output_vout: Option<i32>,
+}
+
+struct OutputRow<'a> {
+    tx_index: usize,
+    txid: &'a [u8],
+    vout: i32,
+    data: String,
+    value: Amount,
+    addresses: &'a [String],
+}
+
+impl<'a> BlockRows<'a> {
+    fn from_block(block: &'a Block) -> AnyResult<BlockRows<'a>> {
+        let time = UNIX_EPOCH + Duration::from_secs(block.time as u64);
+        BlockRows::new(Some(block.height), time, &block.transactions)
+    }
+
+    fn new(
+        height: Option<u32>,
+        time: SystemTime,
+        txs: &'a [BitcoindTransaction],
+    ) -> AnyResult<BlockRows<'a>> {
+        let mut transactions = Vec::with_capacity(txs.len());
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        let mut block_outputs_total = AmountSum::ZERO;
+        for (index, tx) in txs.iter().enumerate() {
+            let txid = tx.txid.as_bytes();
+
+            for (vin, input) in tx.inputs.iter().enumerate() {
+                let (data, output_txid, output_vout) = match input {
+                    TransactionInput::Coinbase { hex } => {
+                        (json!({ "coinbase": hex::encode(hex) }), None, None)
+                    }
+                    TransactionInput::Usual { txid, vout } => (
+                        json!({}),
+                        txid.as_ref().map(|txid| txid.as_bytes()),
+                        Some(*vout as i32),
+                    ),
+                };
+                inputs.push(InputRow {
+                    tx_index: index,
+                    txid,
+                    vin: vin as i32,
+                    data: data.to_string(),
+                    output_txid,
+                    output_vout,
+                });
+            }
+
+            let mut tx_outputs_total = Amount::ZERO;
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                let value = output.value;
+                tx_outputs_total = tx_outputs_total.try_add(value)?;
+
+                let addresses = output.script.addresses.as_slice();
+                let data = OutputData {
+                    value,
+                    addresses: addresses.to_vec(),
+                };
+                outputs.push(OutputRow {
+                    tx_index: index,
+                    txid,
+                    vout: vout as i32,
+                    data: serde_json::to_string(&data)?,
+                    value,
+                    addresses,
+                });
+            }
+            block_outputs_total += tx_outputs_total;
+
+            transactions.push(TransactionRow {
+                index: height.map(|_| index as i32),
+                txid,
+                raw: tx.hex.as_slice(),
+                inputs_count: tx.inputs.len() as i32,
+                outputs_count: tx.outputs.len() as i32,
+                outputs_total: tx_outputs_total,
+            });
+        }
+
+        Ok(BlockRows {
+            height: height.map(|height| height as i32),
+            time,
+            outputs_total: block_outputs_total,
+            transactions,
+            inputs,
+            outputs,
+        })
+    }
+
+    async fn insert_block(
+        &self,
+        tx: &Transaction<'_>,
+        query: &str,
+        block: &Block,
+        inputs_total: Option<AmountSum>,
+    ) -> EmptyResult {
+        let prev_hash = block.prev_hash.unwrap_or_else(H256::zero);
+        let next_hash = block.next_hash.as_ref().map(|hash| hash.as_bytes());
+        tx.execute(
+            query,
+            &[
+                &self.height,
+                &block.hash.as_bytes(),
+                &prev_hash.as_bytes(),
+                &next_hash,
+                &(block.size as i32),
+                &self.time,
+                &(self.transactions.len() as i32),
+                &(self.inputs.len() as i32),
+                &inputs_total,
+                &(self.outputs.len() as i32),
+                &self.outputs_total,
+            ],
+        )
+        .await?;
         Ok(())
     }
 }
+
+// Changes in address history, unspent and stats produced by block (or
+// unconfirmed transactions)
+struct BlockChanges<'a> {
+    inputs_total: AmountSum,
+    tx_inputs_total: Vec<Amount>,
+    stats: HashMap<&'a str, AddressStats>,
+    history: Vec<AddressHistoryRow<'a>>,
+    spent: Vec<(&'a [u8], i32)>,
+    unspent: Vec<AddressUnspentRow<'a>>,
+}
+
+#[derive(Default)]
+struct AddressStats {
+    count_history: i32,
+    count_unspent: i32,
+    received: AmountSum,
+    sent: AmountSum,
+}
+
+struct AddressHistoryRow<'a> {
+    address: &'a str,
+    txid: &'a [u8],
+    tx_index: Option<i32>,
+    received: Amount,
+    sent: Amount,
+}
+
+struct AddressUnspentRow<'a> {
+    address: &'a str,
+    txid: &'a [u8],
+    vout: i32,
+    value: Amount,
+}
+
+impl<'a> BlockChanges<'a> {
+    // `spent` is data of outputs spent in block, by `(txid, vin)` of input
+    fn new(
+        rows: &'a BlockRows<'a>,
+        spent: &'a HashMap<(Vec<u8>, i32), OutputData>,
+    ) -> AnyResult<BlockChanges<'a>> {
+        let block_txids = rows
+            .transactions
+            .iter()
+            .map(|row| row.txid)
+            .collect::<HashSet<&[u8]>>();
+
+        let mut changes = BlockChanges {
+            inputs_total: AmountSum::ZERO,
+            tx_inputs_total: vec![Amount::ZERO; rows.transactions.len()],
+            stats: HashMap::new(),
+            history: vec![],
+            spent: vec![],
+            unspent: vec![],
+        };
+        // `(received, sent)` for every address in every transaction
+        let mut tx_addresses =
+            vec![HashMap::<&str, (Amount, Amount)>::new(); rows.transactions.len()];
+
+        let mut spent_in_block = HashSet::new();
+        for row in rows.inputs.iter() {
+            let (output_txid, output_vout) = match (row.output_txid, row.output_vout) {
+                (Some(txid), Some(vout)) => (txid, vout),
+                _ => continue,
+            };
+
+            let output = spent.get(&(row.txid.to_vec(), row.vin)).ok_or_else(|| {
+                let msg = format!("Spent output not found for input: {}", row.vin);
+                CustomError::new_any(msg)
+            })?;
+            let value = output.value;
+
+            changes.inputs_total += value;
+            let tx_inputs_total = &mut changes.tx_inputs_total[row.tx_index];
+            *tx_inputs_total = tx_inputs_total.try_add(value)?;
+            for address in output.addresses.iter() {
+                let (_received, sent) = tx_addresses[row.tx_index].entry(address).or_default();
+                *sent = sent.try_add(value)?;
+            }
+
+            // Outputs from this block not inserted to unspent
+            if block_txids.contains(output_txid) {
+                spent_in_block.insert((output_txid, output_vout));
+            } else {
+                changes.spent.push((output_txid, output_vout));
+                for address in output.addresses.iter() {
+                    changes.stats.entry(address).or_default().count_unspent -= 1;
+                }
+            }
+        }
+
+        for row in rows.outputs.iter() {
+            let is_unspent = !spent_in_block.contains(&(row.txid, row.vout));
+            for address in row.addresses.iter() {
+                let (received, _sent) = tx_addresses[row.tx_index].entry(address).or_default();
+                *received = received.try_add(row.value)?;
+
+                if is_unspent {
+                    changes.stats.entry(address).or_default().count_unspent += 1;
+                    changes.unspent.push(AddressUnspentRow {
+                        address,
+                        txid: row.txid,
+                        vout: row.vout,
+                        value: row.value,
+                    });
+                }
+            }
+        }
+
+        for (tx, addresses) in rows.transactions.iter().zip(tx_addresses) {
+            for (address, (received, sent)) in addresses.into_iter() {
+                let stats = changes.stats.entry(address).or_default();
+                stats.count_history += 1;
+                stats.received += received;
+                stats.sent += sent;
+
+                changes.history.push(AddressHistoryRow {
+                    address,
+                    txid: tx.txid,
+                    tx_index: tx.index,
+                    received,
+                    sent,
+                });
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::bitcoind::json::{TransactionOutput, TransactionOutputScript};
+
+    // Tables in state after initial sync, so blocks can be applied
+    async fn new_test(schema: &str) -> IndexerDataBase {
+        let db = DataBase::new_test(schema, DATABASE_VERSION, DATABASE_QUERIES).await;
+        let db = IndexerDataBase { db };
+        for step in db.get_transform_steps().await {
+            db.execute_transform_step(step).await.unwrap();
+        }
+        db
+    }
+
+    // Rows of all final tables as JSON, sorted
+    async fn dump(db: &IndexerDataBase, schema: &str) -> Vec<String> {
+        let client = db.db.pool.get().await.unwrap();
+        let mut rows = vec![];
+        for table in &[
+            "blocks",
+            "transactions",
+            "transactions_inputs_outputs",
+            "address_history",
+            "address_unspent",
+            "stats_addresses",
+        ] {
+            let query = format!(
+                "SELECT '{table}: ' || row_to_json(t)::text AS row FROM {}.{table} AS t ORDER BY 1",
+                schema,
+                table = table
+            );
+            for row in client.query(query.as_str(), &[]).await.unwrap() {
+                rows.push(row.get("row"));
+            }
+        }
+        rows
+    }
+
+    // Stats columns: history, unspent, received and sent, every as
+    // `(confirmed, unconfirmed)`
+    async fn stats(db: &IndexerDataBase, schema: &str, address: &str) -> Option<Vec<i64>> {
+        let client = db.db.pool.get().await.unwrap();
+        let query = format!(
+            "SELECT ARRAY[
+                count_history_confirmed, count_history_unconfirmed,
+                count_unspent_confirmed, count_unspent_unconfirmed,
+                received_confirmed, received_unconfirmed,
+                sent_confirmed, sent_unconfirmed
+            ]::int8[] AS stats FROM {}.stats_addresses WHERE address = $1",
+            schema
+        );
+        let row = client.query_opt(query.as_str(), &[&address]).await.unwrap();
+        row.map(|row| row.get("stats"))
+    }
+
+    fn coinbase(height: u32) -> TransactionInput {
+        TransactionInput::Coinbase {
+            hex: height.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn spend(txid: u64, vout: u32) -> TransactionInput {
+        TransactionInput::Usual {
+            txid: Some(H256::from_low_u64_be(txid)),
+            vout,
+        }
+    }
+
+    // Transaction `txid`, outputs are `(value, addresses)`
+    fn tx(
+        txid: u64,
+        inputs: Vec<TransactionInput>,
+        outputs: &[(u64, &[&str])],
+    ) -> BitcoindTransaction {
+        let outputs = outputs
+            .iter()
+            .map(|(value, addresses)| TransactionOutput {
+                value: Amount::from_sat(*value).unwrap(),
+                script: TransactionOutputScript {
+                    hex: vec![],
+                    addresses: addresses
+                        .iter()
+                        .map(|address| address.to_string())
+                        .collect(),
+                },
+            })
+            .collect();
+        BitcoindTransaction {
+            txid: H256::from_low_u64_be(txid),
+            hash: H256::from_low_u64_be(txid),
+            hex: txid.to_le_bytes().to_vec(),
+            inputs,
+            outputs,
+        }
+    }
+
+    fn block(height: u32, transactions: Vec<BitcoindTransaction>) -> Block {
+        let hash = |height: u32| H256::from_low_u64_be(0xb10c_0000 + height as u64);
+        Block {
+            height,
+            hash: hash(height),
+            prev_hash: height.checked_sub(1).map(hash),
+            next_hash: None,
+            transactions,
+            size: 1000,
+            time: 1_600_000_000 + height * 600,
+        }
+    }
+
+    // Data of outputs spent by `block`, like returned on inputs update
+    fn spent_outputs(
+        block: &Block,
+        prev_txs: &[BitcoindTransaction],
+    ) -> HashMap<(Vec<u8>, i32), OutputData> {
+        let txs = prev_txs.iter().chain(block.transactions.iter());
+        let outputs = txs
+            .flat_map(|tx| {
+                tx.outputs
+                    .iter()
+                    .enumerate()
+                    .map(move |(vout, output)| ((tx.txid, vout as u32), output))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut spent = HashMap::new();
+        for tx in block.transactions.iter() {
+            for (vin, input) in tx.inputs.iter().enumerate() {
+                if let TransactionInput::Usual {
+                    txid: Some(txid),
+                    vout,
+                } = input
+                {
+                    let output = outputs[&(*txid, *vout)];
+                    let data = OutputData {
+                        value: output.value,
+                        addresses: output.script.addresses.clone(),
+                    };
+                    spent.insert((tx.txid.as_bytes().to_vec(), vin as i32), data);
+                }
+            }
+        }
+        spent
+    }
+
+    fn sat(value: u64) -> Amount {
+        Amount::from_sat(value).unwrap()
+    }
+
+    #[test]
+    fn database_block_changes() {
+        let prev_txs = vec![
+            tx(1, vec![coinbase(0)], &[(50, &["a"]), (10, &["b"])]),
+            tx(2, vec![spend(1, 1)], &[(10, &["c"])]),
+        ];
+        // Coinbase, multisig output and output spent in the same block
+        let block = block(
+            1,
+            vec![
+                tx(3, vec![coinbase(1)], &[(50, &["d"])]),
+                tx(4, vec![spend(1, 0)], &[(30, &["b"]), (20, &["a", "c"])]),
+                tx(5, vec![spend(4, 0), spend(2, 0)], &[(40, &["d"])]),
+            ],
+        );
+
+        let rows = BlockRows::from_block(&block).unwrap();
+        assert_eq!(rows.outputs_total, sat(140).into());
+        let spent = spent_outputs(&block, &prev_txs);
+        let changes = BlockChanges::new(&rows, &spent).unwrap();
+
+        assert_eq!(changes.inputs_total, sat(90).into());
+        assert_eq!(changes.tx_inputs_total, vec![sat(0), sat(50), sat(40)]);
+
+        // Only outputs from previous blocks removed from unspent
+        let txid = |n: u64| H256::from_low_u64_be(n);
+        let spent = changes
+            .spent
+            .iter()
+            .map(|(txid, vout)| (H256::from_slice(txid), *vout))
+            .collect::<Vec<_>>();
+        assert_eq!(spent, vec![(txid(1), 0), (txid(2), 0)]);
+
+        let mut unspent = changes
+            .unspent
+            .iter()
+            .map(|row| (row.address, H256::from_slice(row.txid), row.vout, row.value))
+            .collect::<Vec<_>>();
+        unspent.sort();
+        assert_eq!(
+            unspent,
+            vec![
+                ("a", txid(4), 1, sat(20)),
+                ("c", txid(4), 1, sat(20)),
+                ("d", txid(3), 0, sat(50)),
+                ("d", txid(5), 0, sat(40)),
+            ]
+        );
+
+        let mut history = changes
+            .history
+            .iter()
+            .map(|row| {
+                let txid = H256::from_slice(row.txid);
+                (row.address, txid, row.tx_index, row.received, row.sent)
+            })
+            .collect::<Vec<_>>();
+        history.sort();
+        assert_eq!(
+            history,
+            vec![
+                ("a", txid(4), Some(1), sat(20), sat(50)),
+                ("b", txid(4), Some(1), sat(30), sat(0)),
+                ("b", txid(5), Some(2), sat(0), sat(30)),
+                ("c", txid(4), Some(1), sat(20), sat(0)),
+                ("c", txid(5), Some(2), sat(0), sat(10)),
+                ("d", txid(3), Some(0), sat(50), sat(0)),
+                ("d", txid(5), Some(2), sat(40), sat(0)),
+            ]
+        );
+
+        // `(count_history, count_unspent, received, sent)`
+        let mut stats = changes
+            .stats
+            .iter()
+            .map(|(address, stats)| {
+                let counts = (stats.count_history, stats.count_unspent);
+                (*address, counts, stats.received, stats.sent)
+            })
+            .collect::<Vec<_>>();
+        stats.sort();
+        let sum = |value| AmountSum::from(sat(value));
+        assert_eq!(
+            stats,
+            vec![
+                ("a", (1, 0), sum(20), sum(50)),
+                ("b", (2, 0), sum(30), sum(30)),
+                ("c", (2, 0), sum(20), sum(10)),
+                ("d", (2, 2), sum(90), sum(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn database_block_changes_missed_output() {
+        let block = block(1, vec![tx(2, vec![spend(1, 0)], &[(10, &["a"])])]);
+        let rows = BlockRows::from_block(&block).unwrap();
+        let spent = HashMap::new();
+        assert!(BlockChanges::new(&rows, &spent).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL, see TELESCOPE_TEST_POSTGRES"]
+    async fn database_remove_block() {
+        let schema = "telescope_test_remove_block";
+        let db = new_test(schema).await;
+
+        let block0 = block(
+            0,
+            vec![
+                tx(1, vec![coinbase(0)], &[(50, &["a"]), (10, &["b"])]),
+                tx(2, vec![spend(1, 1)], &[(10, &["c"])]),
+            ],
+        );
+        db.apply_block(&block0).await.unwrap();
+        let before = dump(&db, schema).await;
+
+        // Spent outputs from previous block and from this block,
+        // new address `d` and multisig output
+        let block1 = block(
+            1,
+            vec![
+                tx(3, vec![coinbase(1)], &[(50, &["d"])]),
+                tx(4, vec![spend(1, 0)], &[(30, &["b"]), (20, &["a", "c"])]),
+                tx(5, vec![spend(4, 0), spend(2, 0)], &[(40, &["d"])]),
+            ],
+        );
+        db.apply_block(&block1).await.unwrap();
+        // Unconfirmed spender of output from removed block
+        let unconfirmed = vec![tx(6, vec![spend(5, 0)], &[(40, &["e"])])];
+        assert!(db.add_unconfirmed(&unconfirmed).await.unwrap());
+        assert_ne!(dump(&db, schema).await, before);
+
+        db.remove_block(1).await.unwrap();
+        assert_eq!(dump(&db, schema).await, before);
+        assert!(db.get_unconfirmed_txids().await.unwrap().is_empty());
+        assert_eq!(
+            db.get_bestblock_info().await.unwrap(),
+            Some((0, block0.hash))
+        );
+        assert_eq!(db.get_bestblock_size().await.unwrap(), Some(1000));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL, see TELESCOPE_TEST_POSTGRES"]
+    async fn database_amount_sum() {
+        let schema = "telescope_test_amount_sum";
+        let db = new_test(schema).await;
+
+        // Received by address and block total greater than `int8`
+        let max = i64::MAX as u64;
+        let block0 = block(
+            0,
+            vec![
+                tx(1, vec![coinbase(0)], &[(max, &["a"])]),
+                tx(2, vec![coinbase(0)], &[(max, &["a"])]),
+            ],
+        );
+        db.apply_block(&block0).await.unwrap();
+
+        let client = db.db.pool.get().await.unwrap();
+        let query = format!(
+            "SELECT
+                (SELECT outputs_total::text FROM {schema}.blocks) AS block,
+                (SELECT received_confirmed::text FROM {schema}.stats_addresses) AS stats",
+            schema = schema
+        );
+        let row = client.query_one(query.as_str(), &[]).await.unwrap();
+        let expected = (max as u128 * 2).to_string();
+        assert_eq!(row.get::<_, String>("block"), expected);
+        assert_eq!(row.get::<_, String>("stats"), expected);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL, see TELESCOPE_TEST_POSTGRES"]
+    async fn database_unconfirmed() {
+        let schema = "telescope_test_unconfirmed";
+        let db = new_test(schema).await;
+        let txid = |n: u64| H256::from_low_u64_be(n);
+        let unconfirmed_txids = || async {
+            let mut txids = db.get_unconfirmed_txids().await.unwrap();
+            txids.sort();
+            txids
+        };
+
+        let block0 = block(
+            0,
+            vec![tx(1, vec![coinbase(0)], &[(50, &["a"]), (10, &["b"])])],
+        );
+        db.apply_block(&block0).await.unwrap();
+        let before = dump(&db, schema).await;
+
+        // Child spend output of parent in the same insert
+        let parent = tx(2, vec![spend(1, 0)], &[(30, &["c"]), (20, &["a"])]);
+        let child = tx(3, vec![spend(2, 0)], &[(30, &["d"])]);
+        let transactions = vec![parent, child];
+        assert!(db.add_unconfirmed(&transactions).await.unwrap());
+        assert_eq!(unconfirmed_txids().await, vec![txid(2), txid(3)]);
+
+        let a = Some(vec![1, 1, 1, 0, 50, 20, 0, 50]);
+        let c = Some(vec![0, 2, 0, 0, 0, 30, 0, 30]);
+        let d = Some(vec![0, 1, 0, 1, 0, 30, 0, 0]);
+        assert_eq!(stats(&db, schema, "a").await, a);
+        assert_eq!(
+            stats(&db, schema, "b").await,
+            Some(vec![1, 0, 1, 0, 10, 0, 0, 0])
+        );
+        assert_eq!(stats(&db, schema, "c").await, c);
+        assert_eq!(stats(&db, schema, "d").await, d);
+
+        // Unknown spent output, nothing added
+        let orphan = tx(4, vec![spend(1, 1), spend(9, 0)], &[(10, &["e"])]);
+        assert!(!db.add_unconfirmed(&[orphan]).await.unwrap());
+        assert_eq!(unconfirmed_txids().await, vec![txid(2), txid(3)]);
+        assert_eq!(stats(&db, schema, "e").await, None);
+
+        // Parent removed with descendants
+        assert_eq!(db.remove_unconfirmed(&[txid(2)]).await.unwrap(), 2);
+        assert_eq!(dump(&db, schema).await, before);
+
+        // Same stats when parent and child added separately
+        for tx in transactions.iter() {
+            assert!(db.add_unconfirmed(std::slice::from_ref(tx)).await.unwrap());
+        }
+        assert_eq!(stats(&db, schema, "a").await, a);
+        assert_eq!(stats(&db, schema, "c").await, c);
+        assert_eq!(stats(&db, schema, "d").await, d);
+
+        // Block with conflicting transaction remove parent and child
+        let block1 = block(
+            1,
+            vec![
+                tx(5, vec![coinbase(1)], &[(50, &["e"])]),
+                tx(6, vec![spend(1, 0)], &[(50, &["f"])]),
+            ],
+        );
+        db.apply_block(&block1).await.unwrap();
+        assert!(unconfirmed_txids().await.is_empty());
+        assert_eq!(
+            stats(&db, schema, "a").await,
+            Some(vec![2, 0, 0, 0, 50, 0, 50, 0])
+        );
+        assert_eq!(stats(&db, schema, "c").await, None);
+        assert_eq!(stats(&db, schema, "d").await, None);
+        assert_eq!(
+            stats(&db, schema, "f").await,
+            Some(vec![1, 0, 1, 0, 50, 0, 0, 0])
+        );
+    }
+}
@@ -1,21 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use futures::future::{maybe_done, poll_fn, BoxFuture, TryFutureExt as _};
+use futures::stream::{self, StreamExt as _, TryStreamExt as _};
 use futures::task::Poll;
-use tokio::sync::{broadcast, Mutex, RwLock};
+use humantime::format_duration;
+use tokio::sync::{broadcast, Mutex, Notify, RwLock};
+use tokio::time::delay_for;
 
-use super::bitcoind::{json::Block, Bitcoind};
+use super::bitcoind::{json::Block, zmq::ZMQSubscription, Bitcoind};
 use super::database::IndexerDataBase;
+use super::mempool;
+use super::reorg;
 use crate::error::CustomError;
 use crate::fixed_hash::H256;
-use crate::logger::info;
+use crate::logger::{error, info};
 use crate::shutdown::Shutdown;
 use crate::{AnyError, AnyResult, AppFutFromArgs, EmptyResult};
 
+// Node status polled with this interval if ZMQ is not used
+static STATUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Without ZMQ messages for this time we poll status anyway
+static STATUS_ZMQ_QUIET_INTERVAL: Duration = Duration::from_secs(5);
+// Mempool compared with node with this interval, or on ZMQ transaction
+// message, but not more often
+static MEMPOOL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Number of new mempool transactions requested in one batch
+static MEMPOOL_TXS_BATCH: usize = 100;
+// Number of block hashes requested in one batch on start sync
+static START_SYNC_HASHES_BATCH: u32 = 500;
+
 // Remove Arc for fields, use Arc for Indexer itself?
 #[derive(Debug)]
 pub struct Indexer {
@@ -23,18 +40,30 @@ pub struct Indexer {
     db: Arc<IndexerDataBase>,
     bitcoind: Arc<Bitcoind>,
     status: Arc<RwLock<IndexerStatus>>,
+    status_changed: Notify,
+    mempool_changed: Notify,
     sync_threads: u32,
+    sync_prefetch_bytes: u64,
+    confirmations: u32,
 }
 
 impl Indexer {
     pub fn from_args(shutdown: Arc<Shutdown>, args: &clap::ArgMatches<'_>) -> AppFutFromArgs {
         // create indexer
         let indexer = Indexer {
-            shutdown,
             db: Arc::new(IndexerDataBase::from_args(args)),
-            bitcoind: Arc::new(Bitcoind::from_args(args)?),
+            bitcoind: Arc::new(Bitcoind::from_args(Arc::clone(&shutdown), args)?),
+            shutdown,
             status: Arc::new(RwLock::new(IndexerStatus::from_args(args))),
+            status_changed: Notify::new(),
+            mempool_changed: Notify::new(),
             sync_threads: args.value_of("sync_threads").unwrap().parse().unwrap(),
+            sync_prefetch_bytes: args
+                .value_of("sync_prefetch_bytes")
+                .unwrap()
+                .parse()
+                .unwrap(),
+            confirmations: args.value_of("confirmations").unwrap().parse().unwrap(),
         };
 
         Ok(Box::pin(async move { indexer.start().await }))
@@ -45,13 +74,21 @@ impl Indexer {
         // Try connect first
         self.connect().await?;
 
+        // Blocks after restart have size similar to stored best block
+        if let Some(size) = self.db.get_bestblock_size().await? {
+            self.bitcoind.set_block_size(size);
+        }
+
         // Initialize status through update before actually start anything.
         let mut status = IndexerStatus::default();
         status.update_node_status(&self.bitcoind).await?;
         self.update_status(status).await;
 
-        // Run sync loops
-        tokio::try_join!(self.start_status_update_loop(), self.start_sync(),)?;
+        // Run sync loops, after initial sync follow node tip
+        tokio::try_join!(self.start_status_update_loop(), async {
+            self.start_sync().await?;
+            self.start_follow().await
+        })?;
         Ok(())
     }
 
@@ -60,20 +97,22 @@ impl Indexer {
     async fn connect(&self) -> EmptyResult {
         tokio::try_join!(
             self.db.validate(&self.shutdown),
-            self.bitcoind.validate(&self.shutdown).map_err(|e| e.into()),
+            self.bitcoind.validate().map_err(|e| e.into()),
         )?;
         Ok(())
     }
 
     async fn update_status(&self, status: IndexerStatus) {
         // Read lock not require block other futures, so we use it for comparison
-        if *self.status.read().await != status {
-            self.status.write().await.merge(status);
+        if *self.status.read().await != status && self.status.write().await.merge(status) {
+            self.status_changed.notify();
         }
     }
 
     // Update bitcoind and service info in loop
     async fn start_status_update_loop(&self) -> EmptyResult {
+        let mut zmq = self.bitcoind.subscribe_zmq()?;
+
         loop {
             // Check that loop should continue
             self.shutdown.is_recv().await?;
@@ -84,33 +123,362 @@ impl Indexer {
             status.update_node_status(&self.bitcoind).await?;
             self.update_status(status).await;
 
-            // Sleep some time, if required
-            let elapsed = ts.elapsed().unwrap();
-            if let Some(sleep_duration) = Duration::from_millis(100).checked_sub(elapsed) {
-                self.shutdown.delay_for(sleep_duration).await?;
+            // Wait notification from ZMQ, otherwise sleep some time, if required
+            if let Some(ref mut subscription) = zmq {
+                if !self.wait_zmq_block(subscription).await? {
+                    error!("ZMQ subscription closed, fallback to polling");
+                    zmq = None;
+                }
+            } else {
+                let elapsed = ts.elapsed().unwrap();
+                if let Some(sleep_duration) = STATUS_POLL_INTERVAL.checked_sub(elapsed) {
+                    self.shutdown.delay_for(sleep_duration).await?;
+                }
             }
         }
     }
 
-    // Initial or catch-up sync
+    // Wait ZMQ message about new block. If socket is quiet for some time we
+    // return anyway, so status will be polled. Return `false` if subscription
+    // is closed.
+    async fn wait_zmq_block(&self, subscription: &mut ZMQSubscription) -> AnyResult<bool> {
+        loop {
+            let msg = tokio::select! {
+                msg = subscription.recv() => msg,
+                _ = delay_for(STATUS_ZMQ_QUIET_INTERVAL) => return Ok(true),
+                e = self.shutdown.wait() => return Err(e.into()),
+            };
+
+            match msg {
+                Some(Ok(msg)) if msg.is_block() => return Ok(true),
+                Some(Ok(_)) => self.mempool_changed.notify(),
+                Some(Err(e)) => error!("ZMQ error: {}", e),
+                None => return Ok(false),
+            }
+        }
+    }
+
+    // Initial sync
     async fn start_sync(&self) -> EmptyResult {
-        // `#created` is for `initial_sync`, everything else for catch-up.
-        let initial_sync = self.db.get_stage().await.0 == "#created";
+        // Node chain can be changed while indexer was stopped
+        self.check_stored_chain().await?;
+
+        // Blocks imported to initial tables only on `#created` stage,
+        // if transform was started we should finish it first.
+        if self.db.get_stage().await.0 == "#created" {
+            self.start_sync_blocks().await?;
+        }
 
+        self.start_transform().await?;
+
+        // Reorg can happen while indexer was stopped
+        self.remove_orphaned_blocks(None).await
+    }
+
+    // Compare stored hashes at best height and sampled heights below with
+    // node best chain. Blocks orphaned by reorg are removed if initial sync
+    // is finished, otherwise and if node chain is different (other genesis
+    // block, node is behind us) we refuse to continue.
+    async fn check_stored_chain(&self) -> EmptyResult {
+        let (best_height, best_hash) = match self.db.get_bestblock_info().await? {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+
+        let node_height = self.bitcoind.get_blockchain_info().await?.blocks;
+        if node_height < best_height {
+            let msg = format!(
+                "Node best height {} is less than stored best height {} ({}), node is syncing or reindexing?",
+                node_height, best_height, best_hash
+            );
+            return Err(CustomError::new_any(msg));
+        }
+
+        let db = &self.db;
+        let node = self.bitcoind.pin_node(best_height);
+        let mismatches = reorg::find_sampled_mismatches(
+            best_height,
+            |height| Box::pin(db.get_block_hash(height)),
+            |height| Box::pin(node.get_block_hash(height).map_err(|e| e.into())),
+        )
+        .await?;
+
+        let height = match mismatches.last() {
+            Some(height) => *height,
+            None => return Ok(()),
+        };
+        if height == 0 {
+            let msg = "Stored genesis block not match to node genesis block, node was switched to other chain? Database should be recreated";
+            return Err(CustomError::new_any(msg));
+        }
+
+        let stage = self.db.get_stage().await.0;
+        if stage != "#synced" {
+            let msg = format!(
+                "Stored block at height {} is not in node best chain (reorg while indexer was stopped?), blocks can not be removed on stage: {}",
+                height, stage
+            );
+            return Err(CustomError::new_any(msg));
+        }
+
+        info!(
+            "Stored block at height {} is not in node best chain, rollback",
+            height
+        );
+        self.remove_orphaned_blocks(None).await
+    }
+
+    // Compare our best block with node chain. If `prev_hash` of incoming
+    // block (or node block hash at our best height, if not specified) not
+    // match to our best block hash, then reorg happened. In this case we
+    // find fork point and remove all orphaned blocks.
+    async fn remove_orphaned_blocks(&self, prev_hash: Option<H256>) -> EmptyResult {
+        let (best_height, best_hash) = match self.db.get_bestblock_info().await? {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+
+        // Same node for whole search, nodes can be on different tips
+        let node = self.bitcoind.pin_node(best_height);
+        let expected_hash = match prev_hash {
+            Some(hash) => Some(hash),
+            None => node.get_block_hash(best_height).await?,
+        };
+        if expected_hash == Some(best_hash) {
+            return Ok(());
+        }
+
+        let db = &self.db;
+        let fork_height = reorg::find_fork_height(
+            best_height,
+            |height| Box::pin(db.get_block_hash(height)),
+            |height| Box::pin(node.get_block_hash(height).map_err(|e| e.into())),
+        )
+        .await?
+        .ok_or_else(|| {
+            let msg = format!(
+                "No common blocks with node chain, best block: {}",
+                best_hash
+            );
+            CustomError::new_any(msg)
+        })?;
+
+        info!(
+            "Reorg detected, best block: {} ({}), fork at: {}",
+            best_height, best_hash, fork_height
+        );
+
+        let ts = SystemTime::now();
+        reorg::remove_blocks(best_height, fork_height, |height| {
+            Box::pin(db.remove_block(height))
+        })
+        .await?;
+
+        let elapsed = format_duration(ts.elapsed().unwrap());
+        info!(
+            "Reorg: removed {} blocks in {}",
+            best_height - fork_height,
+            elapsed
+        );
+
+        Ok(())
+    }
+
+    // Apply new blocks every time when node tip changed, sync mempool when
+    // we are on node tip. Mempool synced not more often than once per
+    // `MEMPOOL_POLL_INTERVAL`, because on busy node ZMQ notify about new
+    // transactions all the time.
+    async fn start_follow(&self) -> EmptyResult {
+        let mut mempool_synced_at: Option<Instant> = None;
+        loop {
+            self.sync_tip().await?;
+
+            // Unconfirmed transactions can spend outputs from blocks which we do not have yet
+            let best_hash = self
+                .db
+                .get_bestblock_info()
+                .await?
+                .map(|(_height, hash)| hash);
+            let mempool_allowed = match mempool_synced_at {
+                Some(ts) => ts.elapsed() >= MEMPOOL_POLL_INTERVAL,
+                None => true,
+            };
+            if mempool_allowed && best_hash == Some(self.status.read().await.node_syncing_hash) {
+                self.sync_mempool().await?;
+                mempool_synced_at = Some(Instant::now());
+            }
+
+            let mempool_delay = mempool_synced_at
+                .and_then(|ts| MEMPOOL_POLL_INTERVAL.checked_sub(ts.elapsed()))
+                .unwrap_or_default();
+            tokio::select! {
+                _ = self.status_changed.notified() => {},
+                _ = async {
+                    self.mempool_changed.notified().await;
+                    delay_for(mempool_delay).await;
+                } => {},
+                _ = delay_for(MEMPOOL_POLL_INTERVAL) => {},
+                e = self.shutdown.wait() => return Err(e.into()),
+            }
+        }
+    }
+
+    // Compare unconfirmed transactions with node mempool: remove evicted
+    // and replaced transactions, add new.
+    async fn sync_mempool(&self) -> EmptyResult {
+        let ts = SystemTime::now();
+
+        let node_txids = self.bitcoind.get_raw_mempool().await?;
+        let node_txids_set = node_txids.iter().collect::<HashSet<&H256>>();
+
+        let removed_txids = self
+            .db
+            .get_unconfirmed_txids()
+            .await?
+            .into_iter()
+            .filter(|txid| !node_txids_set.contains(txid))
+            .collect::<Vec<H256>>();
+        let removed = if removed_txids.is_empty() {
+            0
+        } else {
+            self.db.remove_unconfirmed(&removed_txids).await?
+        };
+
+        // Descendants of removed transactions can be still in mempool,
+        // so we get stored transactions after remove
+        let stored_txids = self
+            .db
+            .get_unconfirmed_txids()
+            .await?
+            .into_iter()
+            .collect::<HashSet<H256>>();
+        let new_txids = node_txids
+            .into_iter()
+            .filter(|txid| !stored_txids.contains(txid))
+            .collect::<Vec<H256>>();
+        let bitcoind = &self.bitcoind;
+        let transactions = stream::iter(new_txids.chunks(MEMPOOL_TXS_BATCH))
+            .map(|txids| bitcoind.get_raw_transactions(txids))
+            .buffer_unordered(self.sync_threads as usize)
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .flatten() // batches
+            .flatten() // removed from mempool
+            .collect::<Vec<_>>();
+        let transactions = mempool::sort_transactions(transactions);
+
+        let mut added = transactions.len();
+        if added > 0 && !self.db.add_unconfirmed(&transactions).await? {
+            // Transaction can spend outputs which we do not have (new block,
+            // or parent removed from mempool), so add one by one
+            added = 0;
+            for tx in transactions.iter() {
+                if self.db.add_unconfirmed(std::slice::from_ref(tx)).await? {
+                    added += 1;
+                }
+            }
+            info!(
+                "Mempool: {} transactions skipped",
+                transactions.len() - added
+            );
+        }
+
+        if added > 0 || removed > 0 {
+            let elapsed = format_duration(ts.elapsed().unwrap());
+            info!(
+                "Mempool: added {}, removed {} transactions in {}",
+                added, removed, elapsed
+            );
+        }
+
+        Ok(())
+    }
+
+    // Add blocks one by one to final tables until we reach node tip.
+    // If next block not connected to our best block, orphaned blocks
+    // removed first.
+    async fn sync_tip(&self) -> EmptyResult {
+        loop {
+            self.shutdown.is_recv().await?;
+
+            let best = self.db.get_bestblock_info().await?;
+            let next_height = best.map_or(0, |(height, _hash)| height + 1);
+            if next_height > self.status.read().await.node_syncing_height {
+                return Ok(());
+            }
+
+            let ts = SystemTime::now();
+            let block = match self.bitcoind.get_block_by_height(next_height).await? {
+                Some(block) => block,
+                // Node chain changed, wait next status update
+                None => return Ok(()),
+            };
+
+            if best.is_some() && block.prev_hash != best.map(|(_height, hash)| hash) {
+                self.remove_orphaned_blocks(block.prev_hash).await?;
+                continue;
+            }
+
+            self.db.apply_block(&block).await?;
+
+            let elapsed = format_duration(ts.elapsed().unwrap());
+            info!(
+                "Block {} ({}) added in {}",
+                block.height, block.hash, elapsed
+            );
+        }
+    }
+
+    // Import blocks to initial tables up to node tip
+    async fn start_sync_blocks(&self) -> EmptyResult {
         let heights = StartSyncBlockHeightsGenerator::new(&self).await?;
 
+        // Fail before start, instead of error on first pruned block
+        if let Some(prune_height) = self.bitcoind.get_prune_height().await? {
+            let height = heights.first_height();
+            if height < prune_height {
+                let msg = format!(
+                    "Node is pruned, blocks available from height {}, but sync require blocks from height {}. Node should be restarted without `-prune` (with `-reindex`)",
+                    prune_height, height
+                );
+                return Err(CustomError::new_any(msg));
+            }
+        }
+
+        // Read blocks from files, if bitcoind datadir specified
+        let files = self.bitcoind.index_block_files().await?;
+
         let bitcoind = Arc::clone(&self.bitcoind);
+        let hashes = Arc::new(StartSyncBlockHashes::new(Arc::clone(&self.bitcoind)));
         let get_block = move |height| -> BoxFuture<'_, AnyResult<Option<Block>>> {
             let client = Arc::clone(&bitcoind);
-            Box::pin(async move { Ok(client.get_block_by_height(height).await?) })
+            let files = files.clone();
+            let hashes = Arc::clone(&hashes);
+            Box::pin(async move {
+                Ok(match files {
+                    Some(ref files) => client.get_block_from_files(files, height).await?,
+                    None => match hashes.get(height).await? {
+                        Some(hash) => client.get_block_by_hash(hash, height).await?,
+                        None => None,
+                    },
+                })
+            })
         };
 
         let prefetch_size = self.sync_threads + 2;
-        let blocks = StartSyncBlocksGenerator::new(heights, get_block, prefetch_size).await;
+        let blocks = StartSyncBlocksGenerator::new(
+            heights,
+            get_block,
+            |block: &Block| block.size,
+            prefetch_size,
+            self.sync_prefetch_bytes,
+        )
+        .await;
 
         let mut tasks = vec![];
 
-        let jobs = if initial_sync { 1 } else { self.sync_threads };
+        let jobs = self.sync_threads;
         for _ in 0..jobs {
             let bblocks = Arc::clone(&blocks);
             let db = Arc::clone(&self.db);
@@ -152,6 +520,80 @@ impl Indexer {
         })
         .await
     }
+
+    // Transform data from initial tables step by step. Every finished step
+    // saved as stage, so after restart we continue from next step.
+    async fn start_transform(&self) -> EmptyResult {
+        if self.db.get_stage().await.0 == "#synced" {
+            return Ok(());
+        }
+
+        for step in self.db.get_transform_steps().await {
+            let ts = SystemTime::now();
+
+            if step == "blocksTransformFnCall" {
+                self.start_transform_blocks().await?;
+            }
+
+            tokio::select! {
+                v = self.db.execute_transform_step(step) => v?,
+                e = self.shutdown.wait() => return Err(e.into()),
+            }
+
+            let elapsed = format_duration(ts.elapsed().unwrap());
+            info!("[db] transform.{} executed in {}", step, elapsed);
+        }
+
+        self.db.save_stage("#synced").await?;
+        info!("[db] transform finished");
+
+        Ok(())
+    }
+
+    // Transform blocks from initial tables in parallel. Already transformed
+    // blocks are skipped, so after restart we continue from unprocessed blocks.
+    async fn start_transform_blocks(&self) -> EmptyResult {
+        let (total, heights) = self.db.get_transform_block_heights().await?;
+        let mut processed = total - heights.len();
+
+        let db = &self.db;
+        let mut results = stream::iter(heights)
+            .map(|height| db.transform_block(height))
+            .buffer_unordered(self.sync_threads as usize);
+
+        let stage = "#transform:blocksTransformFnCall";
+        let mut queries = 0u64;
+        let mut ts = SystemTime::now();
+        loop {
+            let count = tokio::select! {
+                v = results.try_next() => match v? {
+                    Some(count) => count,
+                    None => break,
+                },
+                e = self.shutdown.wait() => return Err(e.into()),
+            };
+
+            processed += 1;
+            queries += count as u64;
+
+            let progress = processed as f64 / total as f64;
+            self.db.set_stage_with_progress(stage, progress).await;
+
+            if ts.elapsed().unwrap() > Duration::from_secs(10) {
+                ts = SystemTime::now();
+                info!(
+                    "[db] transform blocks: {}/{} ({:.2}%), queries: {}",
+                    processed,
+                    total,
+                    progress * 100.0,
+                    queries
+                );
+            }
+        }
+
+        info!("[db] transform blocks finished, queries: {}", queries);
+        Ok(())
+    }
 }
 
 #[derive(Clone, Default, Debug, PartialEq)]
@@ -177,7 +619,8 @@ impl IndexerStatus {
 
     // pub async fn update_service_status(&mut self, indexer: &Indexer) -> EmptyResult { Ok(()) }
 
-    pub fn merge(&mut self, other: IndexerStatus) {
+    // Return `true` if status was changed
+    pub fn merge(&mut self, other: IndexerStatus) -> bool {
         let mut changed = false;
 
         macro_rules! update_field {
@@ -196,12 +639,15 @@ impl IndexerStatus {
         if changed {
             info!("Update status to: {:?}", other);
         }
+
+        changed
     }
 }
 
 // Stream-like, iterator through all required block heights for import.
 struct StartSyncBlockHeightsGenerator {
     finished: bool,
+    confirmations: u32,
     skipped_heights: Vec<u32>,
     status: Arc<RwLock<IndexerStatus>>,
     next_height: u32,
@@ -227,13 +673,39 @@ impl StartSyncBlockHeightsGenerator {
             vec![]
         };
 
-        Ok(StartSyncBlockHeightsGenerator {
+        // In case if `start_height` not zero (only for development).
+        let next_height = std::cmp::max(start_height, db_next_height);
+        Ok(StartSyncBlockHeightsGenerator::from_heights(
+            skipped_heights,
+            next_height,
+            indexer.confirmations,
+            status,
+        ))
+    }
+
+    fn from_heights(
+        mut skipped_heights: Vec<u32>,
+        next_height: u32,
+        confirmations: u32,
+        status: Arc<RwLock<IndexerStatus>>,
+    ) -> StartSyncBlockHeightsGenerator {
+        // Popped from the end, so skipped heights returned in ascending
+        // order and neighbour heights use same batch of hashes
+        skipped_heights.sort_unstable_by(|a, b| b.cmp(a));
+
+        StartSyncBlockHeightsGenerator {
             finished: false,
+            confirmations,
             skipped_heights,
             status,
-            // In case if `start_height` not zero (only for development).
-            next_height: std::cmp::max(start_height, db_next_height),
-        })
+            next_height,
+        }
+    }
+
+    // Smallest height which will be returned
+    pub fn first_height(&self) -> u32 {
+        let skipped = self.skipped_heights.iter().min().copied();
+        skipped.map_or(self.next_height, |height| height.min(self.next_height))
     }
 
     pub async fn next(&mut self) -> Option<u32> {
@@ -248,12 +720,14 @@ impl StartSyncBlockHeightsGenerator {
 
         // Sync up to block depends from `latest` keyword, because sync process
         // can be require a lot of time `end` block should be changed with
-        // every new generated block.
+        // every new generated block. Last `confirmations` blocks are left for
+        // follow, where reorgs are handled. Node chain can be shorter than
+        // `confirmations` (fresh regtest), in this case nothing is synced here.
         let node_height = self.status.read().await.node_syncing_height;
-        let end_height = node_height - 3;
+        let end_height = (node_height + 1).saturating_sub(self.confirmations);
 
-        // Return Some only if `next_height` less or equal `end_height`
-        if self.next_height <= end_height {
+        // Return Some only if `next_height` less than `end_height`
+        if self.next_height < end_height {
             let height = self.next_height;
             self.next_height += 1;
 
@@ -265,20 +739,79 @@ impl StartSyncBlockHeightsGenerator {
     }
 }
 
+// Block hashes for start sync, requested by batches with `getblockhash`
+// instead of one request for every block.
+struct StartSyncBlockHashes {
+    bitcoind: Arc<Bitcoind>,
+    hashes: Mutex<HashMap<u32, H256>>,
+}
+
+impl StartSyncBlockHashes {
+    pub fn new(bitcoind: Arc<Bitcoind>) -> StartSyncBlockHashes {
+        StartSyncBlockHashes {
+            bitcoind,
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, height: u32) -> AnyResult<Option<H256>> {
+        // Lock while batch is fetched, so other heights from batch wait for it
+        let mut hashes = self.hashes.lock().await;
+        if let Some(hash) = hashes.remove(&height) {
+            return Ok(Some(hash));
+        }
+
+        // Heights requested in ascending order, so hashes left from previous
+        // batch are not needed (stored blocks after skipped height, or
+        // heights after sync end)
+        hashes.clear();
+
+        let end = height.saturating_add(START_SYNC_HASHES_BATCH);
+        let batch = self.bitcoind.get_block_hashes(height..end).await?;
+        for (batch_height, hash) in (height..end).zip(batch) {
+            if let Some(hash) = hash {
+                hashes.insert(batch_height, hash);
+            }
+        }
+
+        Ok(hashes.remove(&height))
+    }
+}
+
 // Stream-like, iterator through all blocks for import with prefetch.
 struct StartSyncBlocksGenerator<T> {
     heights: Mutex<StartSyncBlockHeightsGenerator>,
     #[allow(clippy::type_complexity)]
     get_block: Box<dyn Fn(u32) -> BoxFuture<'static, AnyResult<Option<T>>> + Send + Sync + 'static>,
+    block_size: fn(&T) -> u32,
+    prefetch_size: u32,
+    prefetch_bytes: u64,
     blocks_tx: Mutex<broadcast::Sender<()>>,
-    blocks: Mutex<HashMap<u32, Option<AnyResult<Option<T>>>>>,
+    blocks: Mutex<StartSyncBlocks<T>>,
+}
+
+struct StartSyncBlocks<T> {
+    // Fetching (`None`) and fetched blocks by height
+    items: HashMap<u32, Option<AnyResult<Option<T>>>>,
+    // Number of fetching blocks in `items`
+    fetching: u64,
+    // Size of fetched blocks in `items`
+    bytes: u64,
+    // Size of last fetched block, expected size of fetching blocks
+    last_size: Option<u64>,
 }
 
 impl<T: Send + 'static> StartSyncBlocksGenerator<T> {
+    // Blocks are prefetched while number of blocks in list less than
+    // `prefetch_size` and size of blocks less than `prefetch_bytes` (zero for
+    // no limit). Fetching blocks are counted with size of last fetched block.
+    // On early chain blocks are small, so only number of blocks is used there.
     pub async fn new<F>(
         heights: StartSyncBlockHeightsGenerator,
         get_block: F,
+        block_size: fn(&T) -> u32,
         prefetch_size: u32,
+        prefetch_bytes: u64,
     ) -> Arc<StartSyncBlocksGenerator<T>>
     where
         F: Fn(u32) -> BoxFuture<'static, AnyResult<Option<T>>> + Send + Sync + 'static,
@@ -291,23 +824,56 @@ impl<T: Send + 'static> StartSyncBlocksGenerator<T> {
         let gen = Arc::new(StartSyncBlocksGenerator {
             heights: Mutex::new(heights),
             get_block: Box::new(get_block),
+            block_size,
+            prefetch_size,
+            prefetch_bytes,
             blocks_tx: Mutex::new(broadcast::channel(128).0), // 128 should be enough
-            blocks: Mutex::new(HashMap::new()),
+            blocks: Mutex::new(StartSyncBlocks {
+                items: HashMap::new(),
+                fetching: 0,
+                bytes: 0,
+                last_size: None,
+            }),
         });
-        for _ in 0..prefetch_size {
-            gen.prefetch().await;
-        }
+        gen.prefetch(&mut *gen.blocks.lock().await).await;
         gen
     }
 
-    async fn prefetch(self: &Arc<StartSyncBlocksGenerator<T>>) {
-        let mut blocks = self.blocks.lock().await;
-        if let Some(height) = self.heights.lock().await.next().await {
-            if blocks.insert(height, None).is_some() {
+    fn is_prefetch_allowed(&self, blocks: &StartSyncBlocks<T>) -> bool {
+        // At least one block should be fetched, even if it's bigger than budget
+        if blocks.items.is_empty() {
+            return true;
+        }
+
+        if blocks.items.len() >= self.prefetch_size as usize {
+            return false;
+        }
+
+        if self.prefetch_bytes == 0 {
+            return true;
+        }
+
+        // Size of fetching blocks is not known before first block is fetched,
+        // so only one block is fetched at start
+        match blocks.last_size {
+            Some(size) => blocks.bytes + blocks.fetching * size < self.prefetch_bytes,
+            None => false,
+        }
+    }
+
+    // Start fetch blocks while allowed. Called with locked `blocks`, so other
+    // `next` calls do not see empty list before new blocks are added.
+    async fn prefetch(self: &Arc<StartSyncBlocksGenerator<T>>, blocks: &mut StartSyncBlocks<T>) {
+        while self.is_prefetch_allowed(blocks) {
+            let height = match self.heights.lock().await.next().await {
+                Some(height) => height,
+                None => break,
+            };
+
+            if blocks.items.insert(height, None).is_some() {
                 unreachable!("Fetching block duplicating for height: {}", height);
             }
-            // No needed, because not moved to spawn
-            // drop(blocks);
+            blocks.fetching += 1;
 
             let self1 = Arc::clone(self);
             tokio::spawn(async move {
@@ -323,7 +889,13 @@ impl<T: Send + 'static> StartSyncBlocksGenerator<T> {
                 };
 
                 let mut blocks = self1.blocks.lock().await;
-                if blocks.insert(height, Some(result)).is_none() {
+                blocks.fetching -= 1;
+                if let Ok(Some(ref block)) = result {
+                    let size = (self1.block_size)(block) as u64;
+                    blocks.bytes += size;
+                    blocks.last_size = Some(size);
+                }
+                if blocks.items.insert(height, Some(result)).is_none() {
                     unreachable!("No item for block on start sync: {}", height);
                 }
 
@@ -333,10 +905,6 @@ impl<T: Send + 'static> StartSyncBlocksGenerator<T> {
     }
 
     pub async fn next(self: &Arc<StartSyncBlocksGenerator<T>>) -> AnyResult<Option<T>> {
-        // Start fetch block for this request. We do not care if block already
-        // exists in list, if so this block will be for next function call.
-        self.prefetch().await;
-
         // Subscribe before lock list in loop, otherwise we can be trapped.
         let mut rx = self.blocks_tx.lock().await.subscribe();
 
@@ -345,18 +913,27 @@ impl<T: Send + 'static> StartSyncBlocksGenerator<T> {
             let mut blocks = self.blocks.lock().await;
 
             // If list is empty, not more blocks
-            if blocks.is_empty() {
+            if blocks.items.is_empty() {
                 return Ok(None);
             }
 
             // Try get block from list
             let valid_height = blocks
+                .items
                 .iter()
                 .filter(|(_key, value)| value.is_some())
                 .map(|(key, _value)| *key)
                 .min();
             if let Some(height) = valid_height {
-                return blocks.remove(&height).unwrap().unwrap();
+                let result = blocks.items.remove(&height).unwrap().unwrap();
+                if let Ok(Some(ref block)) = result {
+                    blocks.bytes -= (self.block_size)(block) as u64;
+                }
+
+                // Start fetch blocks in place of this block
+                self.prefetch(&mut blocks).await;
+
+                return result;
             }
 
             // drop blocks lock
@@ -367,3 +944,109 @@ impl<T: Send + 'static> StartSyncBlocksGenerator<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future;
+    use serde_json::json;
+
+    use super::*;
+    use crate::bitcoin::bitcoind::tests::{bitcoind, serve_rpc};
+
+    fn status(node_height: u32) -> Arc<RwLock<IndexerStatus>> {
+        Arc::new(RwLock::new(IndexerStatus {
+            node_syncing_height: node_height,
+            ..IndexerStatus::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn start_sync_heights() {
+        let mut heights =
+            StartSyncBlockHeightsGenerator::from_heights(vec![5, 2, 9], 10, 1, status(12));
+        assert_eq!(heights.first_height(), 2);
+
+        let mut result = vec![];
+        while let Some(height) = heights.next().await {
+            result.push(height);
+        }
+        assert_eq!(result, vec![2, 5, 9, 10, 11]);
+        assert_eq!(heights.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn start_sync_hashes() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = Arc::clone(&calls);
+        let url = serve_rpc(move |method, params| match method {
+            "getblockhash" => {
+                calls2.fetch_add(1, Ordering::Relaxed);
+                match params[0].as_u64().unwrap() {
+                    height if height < 1000 => Ok(json!(format!("{:064x}", height + 1))),
+                    _ => Err(-8),
+                }
+            }
+            _ => Err(-32601),
+        });
+        let hashes = StartSyncBlockHashes::new(Arc::new(bitcoind(&[&url])));
+        let hash = |height: u64| Some(H256::from_low_u64_be(height + 1));
+
+        // Skipped heights and then heights from stored best block
+        for height in [3, 4, 5, 700, 701].iter() {
+            assert_eq!(hashes.get(*height).await.unwrap(), hash(*height as u64));
+        }
+        assert_eq!(hashes.hashes.lock().await.len(), 1000 - 702);
+
+        // Used hashes are removed, unused removed on next batch
+        assert_eq!(hashes.get(900).await.unwrap(), hash(900));
+        assert_eq!(hashes.get(1500).await.unwrap(), None);
+        assert!(hashes.hashes.lock().await.is_empty());
+
+        // Only 3 batches were requested
+        assert_eq!(calls.load(Ordering::Relaxed), 3 * 500);
+    }
+
+    // Blocks for heights 0..100, block is own size
+    async fn blocks(prefetch_size: u32, prefetch_bytes: u64) -> Arc<StartSyncBlocksGenerator<u32>> {
+        let heights = StartSyncBlockHeightsGenerator::from_heights(vec![], 0, 1, status(100));
+        let get_block = |_height| -> BoxFuture<'static, AnyResult<Option<u32>>> {
+            Box::pin(future::ok(Some(1000)))
+        };
+        let block_size = |size: &u32| *size;
+        StartSyncBlocksGenerator::new(
+            heights,
+            get_block,
+            block_size,
+            prefetch_size,
+            prefetch_bytes,
+        )
+        .await
+    }
+
+    // Max number of fetching and fetched blocks, while all blocks received
+    async fn max_prefetched(gen: Arc<StartSyncBlocksGenerator<u32>>) -> usize {
+        let mut count = 0;
+        let mut max = 0;
+        while let Some(size) = gen.next().await.unwrap() {
+            assert_eq!(size, 1000);
+            count += 1;
+            max = max.max(gen.blocks.lock().await.items.len());
+        }
+        assert_eq!(count, 100);
+        max
+    }
+
+    #[tokio::test]
+    async fn start_sync_blocks_prefetch() {
+        // Only number of blocks, without bytes limit
+        assert_eq!(max_prefetched(blocks(10, 0).await).await, 10);
+
+        // Fetching blocks are counted with size of last fetched block
+        assert_eq!(max_prefetched(blocks(10, 2500).await).await, 3);
+
+        // Block bigger than limit is fetched alone
+        assert_eq!(max_prefetched(blocks(10, 500).await).await, 1);
+    }
+}
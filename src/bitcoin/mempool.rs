@@ -0,0 +1,114 @@
+// Mempool helpers. Transactions from `getrawmempool` are not ordered, but
+// transaction can be added to database only after transactions which outputs
+// it spend.
+
+use std::collections::HashMap;
+
+use super::bitcoind::json::{Transaction, TransactionInput};
+use crate::fixed_hash::H256;
+
+// Sort transactions, so parents placed before children
+pub fn sort_transactions(transactions: Vec<Transaction>) -> Vec<Transaction> {
+    sort_by_parents(transactions, |tx| {
+        let parents = tx
+            .inputs
+            .iter()
+            .filter_map(|input| match input {
+                TransactionInput::Usual { txid, .. } => *txid,
+                TransactionInput::Coinbase { .. } => None,
+            })
+            .collect();
+        (tx.txid, parents)
+    })
+}
+
+// Sort items by `(id, parents)`, parents not from `items` are ignored.
+// Relative order of independent items is preserved.
+fn sort_by_parents<T, F>(items: Vec<T>, get_ids: F) -> Vec<T>
+where
+    F: Fn(&T) -> (H256, Vec<H256>),
+{
+    let ids = items.iter().map(get_ids).collect::<Vec<_>>();
+    let indices = ids
+        .iter()
+        .enumerate()
+        .map(|(index, (id, _parents))| (*id, index))
+        .collect::<HashMap<H256, usize>>();
+
+    // Iterative DFS, push item to order after all parents
+    let mut visited = vec![false; items.len()];
+    let mut order = Vec::with_capacity(items.len());
+    for start in 0..items.len() {
+        let mut stack = vec![(start, 0)];
+        while let Some((index, parent_pos)) = stack.pop() {
+            if parent_pos == 0 {
+                if visited[index] {
+                    continue;
+                }
+                visited[index] = true;
+            }
+
+            let parents = &ids[index].1;
+            let next_parent = parents[parent_pos..]
+                .iter()
+                .position(|parent| matches!(indices.get(parent), Some(i) if !visited[*i]));
+            match next_parent {
+                Some(pos) => {
+                    let parent_index = indices[&parents[parent_pos + pos]];
+                    stack.push((index, parent_pos + pos + 1));
+                    stack.push((parent_index, 0));
+                }
+                None => order.push(index),
+            }
+        }
+    }
+
+    let mut items = items.into_iter().map(Some).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .map(|index| items[index].take().unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u64) -> H256 {
+        H256::from_low_u64_be(n)
+    }
+
+    fn sort(items: Vec<(u64, Vec<u64>)>) -> Vec<u64> {
+        let items = items
+            .into_iter()
+            .map(|(n, parents)| (id(n), parents.into_iter().map(id).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        sort_by_parents(items, |(id, parents)| (*id, parents.clone()))
+            .into_iter()
+            .map(|(id, _parents)| id.to_low_u64_be())
+            .collect()
+    }
+
+    #[test]
+    fn mempool_sort_independent() {
+        let sorted = sort(vec![(3, vec![]), (1, vec![10]), (2, vec![])]);
+        assert_eq!(sorted, vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn mempool_sort_chain() {
+        let sorted = sort(vec![(3, vec![2]), (1, vec![]), (2, vec![1, 10])]);
+        assert_eq!(sorted, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn mempool_sort_multiple_parents() {
+        let sorted = sort(vec![
+            (4, vec![3, 2]),
+            (2, vec![1]),
+            (3, vec![1]),
+            (1, vec![]),
+        ]);
+        assert_eq!(sorted, vec![1, 3, 2, 4]);
+    }
+}
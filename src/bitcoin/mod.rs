@@ -1,8 +1,14 @@
 pub use self::client::Client;
 pub use self::indexer::Indexer;
+pub use self::params::CoinParams;
 
+mod address;
+mod amount;
 mod bitcoind;
 mod database;
+mod params;
 
 mod client;
 mod indexer;
+mod mempool;
+mod reorg;
@@ -0,0 +1,484 @@
+// Parameters of bitcoin-like coins and their chains. New bitcoin fork should
+// be added only here, everything else (args possible values, node validation,
+// addresses, block files, raw format) use these parameters.
+//
+// Shared values are `const`, because statics can not refer to other statics.
+
+use super::address::AddressPrefixes;
+use super::amount::Amount;
+use super::bitcoind::Format;
+
+#[derive(Debug)]
+pub struct CoinParams {
+    pub name: &'static str,
+    // Node useragent and version requirement, from subversion in `getnetworkinfo`
+    pub useragent: &'static str,
+    pub version: &'static str,
+    // Blocks through REST interface instead of RPC
+    pub rest_blocks: bool,
+    // Transactions in block JSON have `hex`, otherwise only raw format can be used
+    pub json_blocks: bool,
+    pub format: Format,
+    // Not used by indexer yet
+    #[allow(dead_code)]
+    pub coinbase_maturity: u32,
+    pub chains: &'static [ChainParams],
+}
+
+#[derive(Debug)]
+pub struct ChainParams {
+    // Same as `chain` in `getblockchaininfo`
+    pub name: &'static str,
+    pub genesis_hash: &'static str,
+    pub address_prefixes: AddressPrefixes,
+    // Subdirectory in datadir and magic in block files
+    pub datadir: &'static str,
+    pub magic: [u8; 4],
+    // Version requirement in addition to coin one, if chain was added in
+    // later release. Otherwise old node fail later on chain name mismatch.
+    pub version: Option<&'static str>,
+    // Not used by indexer yet
+    #[allow(dead_code)]
+    pub subsidy: Subsidy,
+}
+
+#[derive(Debug)]
+pub enum Subsidy {
+    // Initial subsidy (in satoshis) halved every `interval` blocks
+    Halving { initial: u64, interval: u32 },
+    // Subsidy from height, last value is forever
+    Steps(&'static [(u32, u64)]),
+}
+
+const COIN: u64 = 100_000_000;
+
+const BITCOIN_SUBSIDY: Subsidy = Subsidy::Halving {
+    initial: 50 * COIN,
+    interval: 210_000,
+};
+const REGTEST_SUBSIDY: Subsidy = Subsidy::Halving {
+    initial: 50 * COIN,
+    interval: 150,
+};
+
+const BITCOIN_PREFIXES: AddressPrefixes = AddressPrefixes {
+    pubkey_hash: 0x00,
+    script_hash: 0x05,
+    bech32_hrp: Some("bc"),
+    cashaddr_prefix: None,
+};
+const BITCOIN_TEST_PREFIXES: AddressPrefixes = AddressPrefixes {
+    pubkey_hash: 0x6f,
+    script_hash: 0xc4,
+    bech32_hrp: Some("tb"),
+    cashaddr_prefix: None,
+};
+
+const BITCOIN_GENESIS: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+const BITCOIN_TEST_GENESIS: &str =
+    "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943";
+const BITCOIN_REGTEST_GENESIS: &str =
+    "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+
+const MAIN_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+const TEST_MAGIC: [u8; 4] = [0x0b, 0x11, 0x09, 0x07];
+const REGTEST_MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+// Early blocks had random subsidy, maximum value is used
+const DOGECOIN_SUBSIDY: Subsidy = Subsidy::Steps(&[
+    (0, 1_000_000 * COIN),
+    (100_000, 500_000 * COIN),
+    (145_000, 250_000 * COIN),
+    (200_000, 125_000 * COIN),
+    (300_000, 62_500 * COIN),
+    (400_000, 31_250 * COIN),
+    (500_000, 15_625 * COIN),
+    (600_000, 10_000 * COIN),
+]);
+
+static COINS: &[CoinParams] = &[
+    CoinParams {
+        name: "bitcoin",
+        useragent: "Satoshi",
+        version: ">= 0.19.0",
+        rest_blocks: false,
+        json_blocks: true,
+        format: Format {
+            auxpow: false,
+            mweb: false,
+            cashtokens: false,
+        },
+        coinbase_maturity: 100,
+        chains: &[
+            ChainParams {
+                name: "main",
+                genesis_hash: BITCOIN_GENESIS,
+                address_prefixes: BITCOIN_PREFIXES,
+                datadir: "",
+                magic: MAIN_MAGIC,
+                version: None,
+                subsidy: BITCOIN_SUBSIDY,
+            },
+            ChainParams {
+                name: "test",
+                genesis_hash: BITCOIN_TEST_GENESIS,
+                address_prefixes: BITCOIN_TEST_PREFIXES,
+                datadir: "testnet3",
+                magic: TEST_MAGIC,
+                version: None,
+                subsidy: BITCOIN_SUBSIDY,
+            },
+            ChainParams {
+                name: "testnet4",
+                genesis_hash: "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043",
+                address_prefixes: BITCOIN_TEST_PREFIXES,
+                datadir: "testnet4",
+                magic: [0x1c, 0x16, 0x3f, 0x28],
+                version: Some(">= 28.0.0"),
+                subsidy: BITCOIN_SUBSIDY,
+            },
+            // Default signet, custom signets (`-signetchallenge`) have other
+            // genesis block and magic
+            ChainParams {
+                name: "signet",
+                genesis_hash: "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+                address_prefixes: BITCOIN_TEST_PREFIXES,
+                datadir: "signet",
+                magic: [0x0a, 0x03, 0xcf, 0x40],
+                version: Some(">= 0.21.0"),
+                subsidy: BITCOIN_SUBSIDY,
+            },
+            ChainParams {
+                name: "regtest",
+                genesis_hash: BITCOIN_REGTEST_GENESIS,
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x6f,
+                    script_hash: 0xc4,
+                    bech32_hrp: Some("bcrt"),
+                    cashaddr_prefix: None,
+                },
+                datadir: "regtest",
+                magic: REGTEST_MAGIC,
+                version: None,
+                subsidy: REGTEST_SUBSIDY,
+            },
+        ],
+    },
+    // Bitcoin Cash use different network magic, but in files it's still same
+    // as in bitcoin
+    CoinParams {
+        name: "bitcoincash",
+        useragent: "Bitcoin Cash Node",
+        version: ">= 26.0.0",
+        rest_blocks: true,
+        json_blocks: true,
+        format: Format {
+            auxpow: false,
+            mweb: false,
+            cashtokens: true,
+        },
+        coinbase_maturity: 100,
+        chains: &[
+            ChainParams {
+                name: "main",
+                genesis_hash: BITCOIN_GENESIS,
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x00,
+                    script_hash: 0x05,
+                    bech32_hrp: None,
+                    cashaddr_prefix: Some("bitcoincash"),
+                },
+                datadir: "",
+                magic: MAIN_MAGIC,
+                version: None,
+                subsidy: BITCOIN_SUBSIDY,
+            },
+            ChainParams {
+                name: "test",
+                genesis_hash: BITCOIN_TEST_GENESIS,
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x6f,
+                    script_hash: 0xc4,
+                    bech32_hrp: None,
+                    cashaddr_prefix: Some("bchtest"),
+                },
+                datadir: "testnet3",
+                magic: TEST_MAGIC,
+                version: None,
+                subsidy: BITCOIN_SUBSIDY,
+            },
+            ChainParams {
+                name: "regtest",
+                genesis_hash: BITCOIN_REGTEST_GENESIS,
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x6f,
+                    script_hash: 0xc4,
+                    bech32_hrp: None,
+                    cashaddr_prefix: Some("bchreg"),
+                },
+                datadir: "regtest",
+                magic: REGTEST_MAGIC,
+                version: None,
+                subsidy: REGTEST_SUBSIDY,
+            },
+        ],
+    },
+    // Transactions in block JSON do not have `hex`
+    CoinParams {
+        name: "dogecoin",
+        useragent: "Shibetoshi",
+        version: ">= 1.14.0",
+        rest_blocks: true,
+        json_blocks: false,
+        format: Format {
+            auxpow: true,
+            mweb: false,
+            cashtokens: false,
+        },
+        // 30 before height 145000
+        coinbase_maturity: 240,
+        chains: &[
+            ChainParams {
+                name: "main",
+                genesis_hash: "1a91e3dace36e2be3bf030a65679fe821aa1d6ef92e7c9902eb318182c355691",
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x1e,
+                    script_hash: 0x16,
+                    bech32_hrp: None,
+                    cashaddr_prefix: None,
+                },
+                datadir: "",
+                magic: [0xc0, 0xc0, 0xc0, 0xc0],
+                version: None,
+                subsidy: DOGECOIN_SUBSIDY,
+            },
+            ChainParams {
+                name: "test",
+                genesis_hash: "bb0a78264637406b6360aad926284d544d7049f45189db5664f3c4d07350559e",
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x71,
+                    script_hash: 0xc4,
+                    bech32_hrp: None,
+                    cashaddr_prefix: None,
+                },
+                datadir: "testnet3",
+                magic: [0xfc, 0xc1, 0xb7, 0xdc],
+                version: None,
+                subsidy: DOGECOIN_SUBSIDY,
+            },
+            ChainParams {
+                name: "regtest",
+                genesis_hash: "3d2160a3b5dc4a9d62e7e66a295f70313ac808440ef7400d6c0772171ce973a5",
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x6f,
+                    script_hash: 0xc4,
+                    bech32_hrp: None,
+                    cashaddr_prefix: None,
+                },
+                datadir: "regtest",
+                magic: REGTEST_MAGIC,
+                version: None,
+                subsidy: Subsidy::Steps(&[
+                    (0, 500_000 * COIN),
+                    (150, 250_000 * COIN),
+                    (300, 125_000 * COIN),
+                    (450, 62_500 * COIN),
+                    (600, 31_250 * COIN),
+                    (750, 15_625 * COIN),
+                    (900, 10_000 * COIN),
+                ]),
+            },
+        ],
+    },
+    // MWEB require 0.21.2
+    CoinParams {
+        name: "litecoin",
+        useragent: "LitecoinCore",
+        version: ">= 0.21.2",
+        rest_blocks: true,
+        json_blocks: true,
+        format: Format {
+            auxpow: false,
+            mweb: true,
+            cashtokens: false,
+        },
+        coinbase_maturity: 100,
+        chains: &[
+            ChainParams {
+                name: "main",
+                genesis_hash: "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2",
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x30,
+                    script_hash: 0x32,
+                    bech32_hrp: Some("ltc"),
+                    cashaddr_prefix: None,
+                },
+                datadir: "",
+                magic: [0xfb, 0xc0, 0xb6, 0xdb],
+                version: None,
+                subsidy: Subsidy::Halving {
+                    initial: 50 * COIN,
+                    interval: 840_000,
+                },
+            },
+            ChainParams {
+                name: "test",
+                genesis_hash: "4966625a4b2851d9fdee139e56211a0d88575f59ed816ff5e6a63deb4e3e29a0",
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x6f,
+                    script_hash: 0x3a,
+                    bech32_hrp: Some("tltc"),
+                    cashaddr_prefix: None,
+                },
+                datadir: "testnet4",
+                magic: [0xfd, 0xd2, 0xc8, 0xf1],
+                version: None,
+                subsidy: Subsidy::Halving {
+                    initial: 50 * COIN,
+                    interval: 840_000,
+                },
+            },
+            ChainParams {
+                name: "regtest",
+                genesis_hash: "530827f38f93b43ed12af0b3ad25a288dc02ed74d6d7857862df51fc56c416f9",
+                address_prefixes: AddressPrefixes {
+                    pubkey_hash: 0x6f,
+                    script_hash: 0x3a,
+                    bech32_hrp: Some("rltc"),
+                    cashaddr_prefix: None,
+                },
+                datadir: "regtest",
+                magic: REGTEST_MAGIC,
+                version: None,
+                subsidy: REGTEST_SUBSIDY,
+            },
+        ],
+    },
+];
+
+impl CoinParams {
+    pub fn get(coin: &str, chain: &str) -> Option<(&'static CoinParams, &'static ChainParams)> {
+        let coin = COINS.iter().find(|params| params.name == coin)?;
+        let chain = coin.chains.iter().find(|params| params.name == chain)?;
+        Some((coin, chain))
+    }
+
+    pub fn version_requirements(&self, chain: &ChainParams) -> Vec<&'static str> {
+        let mut requirements = vec![self.version];
+        requirements.extend(chain.version);
+        requirements
+    }
+
+    // Possible values for args
+    pub fn coin_names() -> Vec<&'static str> {
+        COINS.iter().map(|params| params.name).collect()
+    }
+
+    pub fn chain_names() -> Vec<&'static str> {
+        let mut names = vec![];
+        for params in COINS.iter().flat_map(|coin| coin.chains.iter()) {
+            if !names.contains(&params.name) {
+                names.push(params.name);
+            }
+        }
+        names
+    }
+}
+
+impl Subsidy {
+    #[allow(dead_code)]
+    pub fn get(&self, height: u32) -> Amount {
+        let value = match self {
+            Subsidy::Halving { initial, interval } => {
+                let halvings = height / interval;
+                if halvings >= 64 {
+                    0
+                } else {
+                    initial >> halvings
+                }
+            }
+            Subsidy::Steps(steps) => steps
+                .iter()
+                .take_while(|(from, _value)| *from <= height)
+                .last()
+                .map_or(0, |(_from, value)| *value),
+        };
+        Amount::from_sat(value).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::{Version, VersionReq};
+
+    use super::*;
+
+    #[test]
+    fn params_subsidy() {
+        let subsidy = |coin, chain, height| {
+            let (_coin, chain) = CoinParams::get(coin, chain).unwrap();
+            chain.subsidy.get(height).to_string()
+        };
+
+        assert_eq!(subsidy("bitcoin", "main", 0), "50.00000000");
+        assert_eq!(subsidy("bitcoin", "main", 840_000), "3.12500000");
+        assert_eq!(subsidy("bitcoin", "main", 64 * 210_000), "0.00000000");
+        assert_eq!(subsidy("bitcoin", "regtest", 150), "25.00000000");
+        assert_eq!(subsidy("litecoin", "main", 840_000), "25.00000000");
+        assert_eq!(subsidy("dogecoin", "main", 145_000), "250000.00000000");
+        assert_eq!(subsidy("dogecoin", "main", 5_000_000), "10000.00000000");
+        assert_eq!(subsidy("dogecoin", "regtest", 150), "250000.00000000");
+
+        // Every chain have subsidy for genesis block
+        for coin in COINS {
+            for chain in coin.chains {
+                assert!(chain.subsidy.get(0) > Amount::ZERO, "{}", chain.name);
+            }
+        }
+    }
+
+    #[test]
+    fn params_coinbase_maturity() {
+        let maturity = |coin| CoinParams::get(coin, "main").unwrap().0.coinbase_maturity;
+
+        assert_eq!(maturity("bitcoin"), 100);
+        assert_eq!(maturity("bitcoincash"), 100);
+        assert_eq!(maturity("dogecoin"), 240);
+        assert_eq!(maturity("litecoin"), 100);
+    }
+
+    #[test]
+    fn params_versions() {
+        let matches = |coin, chain, version| {
+            let (coin, chain) = CoinParams::get(coin, chain).unwrap();
+            let version = Version::parse(version).unwrap();
+            coin.version_requirements(chain)
+                .iter()
+                .all(|requirement| VersionReq::parse(requirement).unwrap().matches(&version))
+        };
+
+        assert!(matches("bitcoin", "main", "0.19.0"));
+        assert!(!matches("bitcoin", "main", "0.18.1"));
+        assert!(!matches("bitcoin", "signet", "0.20.1"));
+        assert!(matches("bitcoin", "signet", "0.21.0"));
+        assert!(!matches("bitcoin", "testnet4", "27.1.0"));
+        assert!(matches("bitcoin", "testnet4", "28.0.0"));
+        assert!(matches("litecoin", "test", "0.21.2"));
+    }
+
+    #[test]
+    fn params_names() {
+        assert_eq!(
+            CoinParams::coin_names(),
+            vec!["bitcoin", "bitcoincash", "dogecoin", "litecoin"]
+        );
+        assert_eq!(
+            CoinParams::chain_names(),
+            vec!["main", "test", "testnet4", "signet", "regtest"]
+        );
+
+        assert!(CoinParams::get("bitcoin", "signet").is_some());
+        assert!(CoinParams::get("litecoin", "signet").is_none());
+        assert!(CoinParams::get("namecoin", "main").is_none());
+    }
+}
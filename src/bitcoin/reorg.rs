@@ -0,0 +1,223 @@
+// Chain reorganization helpers. Functions are generic over hash getters and
+// block remover, so same code used with database/bitcoind and in tests.
+
+use futures::future::BoxFuture;
+
+use crate::fixed_hash::H256;
+use crate::{AnyResult, EmptyResult};
+
+// Walk back from `best_height` until stored hash match to node hash.
+// Return height of last common block, or `None` if chains have nothing common.
+pub async fn find_fork_height<'a, S, N>(
+    best_height: u32,
+    get_stored_hash: S,
+    get_node_hash: N,
+) -> AnyResult<Option<u32>>
+where
+    S: Fn(u32) -> BoxFuture<'a, AnyResult<Option<H256>>>,
+    N: Fn(u32) -> BoxFuture<'a, AnyResult<Option<H256>>>,
+{
+    let mut height = best_height;
+    loop {
+        let (stored, node) = tokio::try_join!(get_stored_hash(height), get_node_hash(height))?;
+        if stored.is_some() && stored == node {
+            return Ok(Some(height));
+        }
+
+        if height == 0 {
+            return Ok(None);
+        }
+        height -= 1;
+    }
+}
+
+// Heights for quick check of stored chain: best height, then exponentially
+// further from it (1, 2, 4, ... blocks back) and genesis.
+pub fn sample_heights(best_height: u32) -> Vec<u32> {
+    let mut heights = vec![best_height];
+    let mut step = Some(1u32);
+    while let Some(height) = step.and_then(|step| best_height.checked_sub(step)) {
+        heights.push(height);
+        step = step.and_then(|step| step.checked_mul(2));
+    }
+    if heights.last() != Some(&0) {
+        heights.push(0);
+    }
+    heights
+}
+
+// Compare stored and node hashes at sampled heights, return heights where
+// stored block not match to node block. Heights without stored block skipped.
+pub async fn find_sampled_mismatches<'a, S, N>(
+    best_height: u32,
+    get_stored_hash: S,
+    get_node_hash: N,
+) -> AnyResult<Vec<u32>>
+where
+    S: Fn(u32) -> BoxFuture<'a, AnyResult<Option<H256>>>,
+    N: Fn(u32) -> BoxFuture<'a, AnyResult<Option<H256>>>,
+{
+    let mut mismatches = vec![];
+    for height in sample_heights(best_height) {
+        let (stored, node) = tokio::try_join!(get_stored_hash(height), get_node_hash(height))?;
+        if stored.is_some() && stored != node {
+            mismatches.push(height);
+        }
+    }
+    Ok(mismatches)
+}
+
+// Remove blocks from `best_height` down to `fork_height` (exclusive).
+// Blocks removed from top, so every step leave consistent chain.
+pub async fn remove_blocks<'a, R>(
+    best_height: u32,
+    fork_height: u32,
+    remove_block: R,
+) -> EmptyResult
+where
+    R: Fn(u32) -> BoxFuture<'a, EmptyResult>,
+{
+    for height in (fork_height + 1..=best_height).rev() {
+        remove_block(height).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Build chain with `len` blocks, hashes after `fork_height` depends from `branch`.
+    fn build_chain(len: u32, fork_height: u32, branch: u64) -> Vec<H256> {
+        (0..len)
+            .map(|height| {
+                let branch = if height > fork_height { branch } else { 0 };
+                H256::from_low_u64_be((branch << 32) | height as u64)
+            })
+            .collect()
+    }
+
+    fn get_hash(chain: &Mutex<Vec<H256>>, height: u32) -> BoxFuture<'_, AnyResult<Option<H256>>> {
+        let hash = chain.lock().unwrap().get(height as usize).cloned();
+        Box::pin(async move { Ok(hash) })
+    }
+
+    // Apply node chain to stored chain like indexer do: find fork, remove orphaned blocks, push new.
+    async fn sync(stored: &Mutex<Vec<H256>>, node: &Mutex<Vec<H256>>) -> AnyResult<u32> {
+        let best_height = stored.lock().unwrap().len() as u32 - 1;
+        let fork_height = find_fork_height(
+            best_height,
+            |height| get_hash(stored, height),
+            |height| get_hash(node, height),
+        )
+        .await?
+        .expect("chains should have common block");
+
+        remove_blocks(best_height, fork_height, |height| {
+            let mut chain = stored.lock().unwrap();
+            assert_eq!(chain.len() as u32, height + 1, "only tip can be removed");
+            chain.pop();
+            Box::pin(async { Ok(()) })
+        })
+        .await?;
+
+        let node = node.lock().unwrap();
+        let mut chain = stored.lock().unwrap();
+        let start = chain.len();
+        chain.extend_from_slice(&node[start..]);
+
+        Ok(best_height - fork_height)
+    }
+
+    #[tokio::test]
+    async fn reorg_no_fork() {
+        let stored = Mutex::new(build_chain(10, 10, 1));
+        let node = Mutex::new(build_chain(12, 12, 1));
+
+        let removed = sync(&stored, &node).await.unwrap();
+        assert_eq!(removed, 0);
+        assert_eq!(*stored.lock().unwrap(), *node.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn reorg_fork() {
+        let stored = Mutex::new(build_chain(10, 6, 1));
+        let node = Mutex::new(build_chain(11, 6, 2));
+
+        let best_height = 9;
+        let fork_height = find_fork_height(
+            best_height,
+            |height| get_hash(&stored, height),
+            |height| get_hash(&node, height),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fork_height, Some(6));
+
+        let removed = sync(&stored, &node).await.unwrap();
+        assert_eq!(removed, 3);
+        assert_eq!(*stored.lock().unwrap(), *node.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn reorg_node_chain_shorter() {
+        let stored = Mutex::new(build_chain(10, 4, 1));
+        let node = Mutex::new(build_chain(7, 4, 2));
+
+        let removed = sync(&stored, &node).await.unwrap();
+        assert_eq!(removed, 5);
+        assert_eq!(*stored.lock().unwrap(), *node.lock().unwrap());
+    }
+
+    #[test]
+    fn reorg_sample_heights() {
+        assert_eq!(sample_heights(0), vec![0]);
+        assert_eq!(sample_heights(1), vec![1, 0]);
+        assert_eq!(sample_heights(10), vec![10, 9, 8, 6, 2, 0]);
+        assert_eq!(sample_heights(u32::MAX).len(), 34);
+    }
+
+    #[tokio::test]
+    async fn reorg_sampled_mismatches() {
+        let stored = Mutex::new(build_chain(10, 6, 1));
+        let node = Mutex::new(build_chain(11, 6, 2));
+        let mismatches = find_sampled_mismatches(
+            9,
+            |height| get_hash(&stored, height),
+            |height| get_hash(&node, height),
+        )
+        .await
+        .unwrap();
+        assert_eq!(mismatches, vec![9, 8, 7]);
+
+        // Node chain is shorter than stored
+        let node = Mutex::new(build_chain(8, 6, 1));
+        let mismatches = find_sampled_mismatches(
+            9,
+            |height| get_hash(&stored, height),
+            |height| get_hash(&node, height),
+        )
+        .await
+        .unwrap();
+        assert_eq!(mismatches, vec![9, 8]);
+    }
+
+    #[tokio::test]
+    async fn reorg_no_common_block() {
+        let stored = build_chain(5, 0, 1);
+        let mut node = build_chain(5, 0, 2);
+        node[0] = H256::repeat_byte(0xff);
+        let (stored, node) = (Mutex::new(stored), Mutex::new(node));
+
+        let fork_height = find_fork_height(
+            4,
+            |height| get_hash(&stored, height),
+            |height| get_hash(&node, height),
+        )
+        .await
+        .unwrap();
+        assert_eq!(fork_height, None);
+    }
+}
@@ -1,7 +1,7 @@
--- log10(21*1e6*1e8) = ~15.32
--- name: btcValue
-CREATE DOMAIN {SCHEMA}.btc_value AS numeric(30, 0);
-
+-- All amounts are in satoshis. Values in transaction limited by `MAX_MONEY`
+-- in consensus and fit to `int8`. Sums over many transactions (block totals
+-- and address stats) are `numeric`, because total supply of Dogecoin is
+-- greater than `int8` (~9.22e18 satoshis).
 -- name: blocks
 CREATE TABLE {SCHEMA}.blocks (
   processed boolean NOT NULL DEFAULT FALSE,
@@ -13,9 +13,9 @@ CREATE TABLE {SCHEMA}.blocks (
   time timestamp without time zone NOT NULL,
   transactions_count int4 NOT NULL,
   inputs_count int4 NOT NULL,
-  inputs_total {SCHEMA}.btc_value, -- `NOT NULL` will be added on transform
+  inputs_total numeric(30,0), -- `NOT NULL` will be added on transform
   outputs_count int4 NOT NULL,
-  outputs_total {SCHEMA}.btc_value NOT NULL
+  outputs_total numeric(30,0) NOT NULL
 );
 
 -- We need initial, because otherwise `inputs_total` will be filled on transform.
@@ -29,9 +29,9 @@ CREATE TABLE {SCHEMA}.transactions_initial (
   raw bytea NOT NULL,
   time timestamp without time zone NOT NULL,
   inputs_count int4 NOT NULL,
-  -- inputs_total {SCHEMA}.btc_value NOT NULL,
+  -- inputs_total int8 NOT NULL,
   outputs_count int4 NOT NULL,
-  outputs_total {SCHEMA}.btc_value NOT NULL
+  outputs_total int8 NOT NULL
 );
 
 -- name: transactionsInitialBlockHeightIdx
@@ -45,9 +45,9 @@ CREATE TABLE {SCHEMA}.transactions (
   raw bytea NOT NULL,
   time timestamp without time zone NOT NULL,
   inputs_count int4 NOT NULL,
-  inputs_total {SCHEMA}.btc_value NOT NULL,
+  inputs_total int8 NOT NULL,
   outputs_count int4 NOT NULL,
-  outputs_total {SCHEMA}.btc_value NOT NULL
+  outputs_total int8 NOT NULL
 );
 
 -- name: transactionsInputs
@@ -87,8 +87,8 @@ CREATE TABLE {SCHEMA}.address_history (
   txid bytea NOT NULL,
   tx_index int4, -- Used for pagination, when confirmed
   time timestamp without time zone NOT NULL, -- Used for pagination, when unconfirmed
-  received {SCHEMA}.btc_value NOT NULL,
-  sent {SCHEMA}.btc_value NOT NULL
+  received int8 NOT NULL,
+  sent int8 NOT NULL
 );
 
 -- name: addressUnspent
@@ -97,7 +97,7 @@ CREATE TABLE {SCHEMA}.address_unspent (
   block_height int4,
   txid bytea NOT NULL,
   vout int4 NOT NULL,
-  value {SCHEMA}.btc_value NOT NULL
+  value int8 NOT NULL
 );
 
 -- name: statsAddresses
@@ -107,8 +107,8 @@ CREATE TABLE {SCHEMA}.stats_addresses (
   count_history_unconfirmed int4 NOT NULL,
   count_unspent_confirmed int4 NOT NULL,
   count_unspent_unconfirmed int4 NOT NULL,
-  received_confirmed {SCHEMA}.btc_value NOT NULL,
-  received_unconfirmed {SCHEMA}.btc_value NOT NULL,
-  sent_confirmed {SCHEMA}.btc_value NOT NULL,
-  sent_unconfirmed {SCHEMA}.btc_value NOT NULL
+  received_confirmed numeric(30,0) NOT NULL,
+  received_unconfirmed numeric(30,0) NOT NULL,
+  sent_confirmed numeric(30,0) NOT NULL,
+  sent_unconfirmed numeric(30,0) NOT NULL
 );
@@ -2,12 +2,12 @@
 -- name: blocksInsertOne
 INSERT INTO {SCHEMA}.blocks (
   height, hash, prev_hash, next_hash,
-  size, transactions_count,
+  size, time, transactions_count,
   inputs_count, inputs_total, outputs_count, outputs_total
 ) VALUES (
   $1, $2, $3, $4,
-  $5, $6,
-  $7, $8, $9, $10
+  $5, $6, $7,
+  $8, $9, $10, $11
 );
 
 -- name: blocksSelectBestInfo
@@ -20,27 +20,364 @@ ORDER BY
 LIMIT
   1;
 
+-- name: blocksSelectBestSize
+SELECT
+  size
+FROM
+  {SCHEMA}.blocks
+ORDER BY
+  height DESC
+LIMIT
+  1;
+
 -- name: blocksDeleteByHeight
 DELETE FROM {SCHEMA}.blocks WHERE height = $1;
 
 
 -- Transactions, initial sync
--- name: transactionsInitialInsertMany
-INSERT INTO {SCHEMA}.transactions_initial (
+-- name: transactionsInitialCopy
+COPY {SCHEMA}.transactions_initial (
   block_height, index, txid, raw,
   time,
   inputs_count, outputs_count, outputs_total
-) VALUES {VALUES};
+) FROM STDIN BINARY;
 
--- name: transactionsInitialInsertInputs
-INSERT INTO {SCHEMA}.transactions_inputs (
+-- name: transactionsInitialCopyInputs
+COPY {SCHEMA}.transactions_inputs (
   block_height, txid, vin, data, output_txid, output_vout
-) VALUES {VALUES};
+) FROM STDIN BINARY;
 
--- name: transactionsInitialInsertOutputs
-INSERT INTO {SCHEMA}.transactions_outputs (
+-- name: transactionsInitialCopyOutputs
+COPY {SCHEMA}.transactions_outputs (
   block_height, txid, vout, data
-) VALUES {VALUES};
+) FROM STDIN BINARY;
 
 
 -- Transactions
+-- name: transactionsInsertMany
+INSERT INTO {SCHEMA}.transactions (
+  block_height, index, txid, raw,
+  time,
+  inputs_count, inputs_total, outputs_count, outputs_total
+) VALUES {VALUES};
+
+-- name: transactionsInputsOutputsInsertOutputs
+INSERT INTO {SCHEMA}.transactions_inputs_outputs (
+  output_block_height, output_txid, output_vout, output_data
+) VALUES {VALUES};
+
+-- name: transactionsInputsOutputsInsertCoinbase
+INSERT INTO {SCHEMA}.transactions_inputs_outputs (
+  input_block_height, input_txid, input_vin, input_data
+) VALUES {VALUES};
+
+-- name: transactionsInputsOutputsUpdateInputs
+UPDATE {SCHEMA}.transactions_inputs_outputs
+SET
+  input_block_height = v.block_height,
+  input_txid = v.txid,
+  input_vin = v.vin,
+  input_data = v.data
+FROM (VALUES {VALUES}) AS v(block_height, txid, vin, data, output_txid, output_vout)
+WHERE
+  {SCHEMA}.transactions_inputs_outputs.output_txid = v.output_txid AND
+  {SCHEMA}.transactions_inputs_outputs.output_vout = v.output_vout AND
+  {SCHEMA}.transactions_inputs_outputs.input_txid IS NULL
+RETURNING v.txid, v.vin, {SCHEMA}.transactions_inputs_outputs.output_data;
+
+-- name: addressHistoryInsertMany
+INSERT INTO {SCHEMA}.address_history (
+  address, block_height, txid, tx_index, time, received, sent
+) VALUES {VALUES};
+
+-- name: addressUnspentInsertMany
+INSERT INTO {SCHEMA}.address_unspent (
+  address, block_height, txid, vout, value
+) VALUES {VALUES};
+
+-- name: addressUnspentDeleteMany
+DELETE FROM {SCHEMA}.address_unspent WHERE (txid, vout) IN (VALUES {VALUES});
+
+-- name: statsAddressesUpsertMany
+INSERT INTO {SCHEMA}.stats_addresses (
+  address,
+  count_history_confirmed,
+  count_history_unconfirmed,
+  count_unspent_confirmed,
+  count_unspent_unconfirmed,
+  received_confirmed,
+  received_unconfirmed,
+  sent_confirmed,
+  sent_unconfirmed
+)
+SELECT
+  address,
+  count_history, 0,
+  count_unspent, 0,
+  received, 0,
+  sent, 0
+FROM (VALUES {VALUES}) AS v(address, count_history, count_unspent, received, sent)
+ON CONFLICT (address) DO UPDATE SET
+  count_history_confirmed = {SCHEMA}.stats_addresses.count_history_confirmed + EXCLUDED.count_history_confirmed,
+  count_unspent_confirmed = {SCHEMA}.stats_addresses.count_unspent_confirmed + EXCLUDED.count_unspent_confirmed,
+  received_confirmed = {SCHEMA}.stats_addresses.received_confirmed + EXCLUDED.received_confirmed,
+  sent_confirmed = {SCHEMA}.stats_addresses.sent_confirmed + EXCLUDED.sent_confirmed;
+
+-- name: statsAddressesUpsertManyUnconfirmed
+INSERT INTO {SCHEMA}.stats_addresses (
+  address,
+  count_history_confirmed,
+  count_history_unconfirmed,
+  count_unspent_confirmed,
+  count_unspent_unconfirmed,
+  received_confirmed,
+  received_unconfirmed,
+  sent_confirmed,
+  sent_unconfirmed
+)
+SELECT
+  address,
+  0, count_history,
+  0, count_unspent,
+  0, received,
+  0, sent
+FROM (VALUES {VALUES}) AS v(address, count_history, count_unspent, received, sent)
+ON CONFLICT (address) DO UPDATE SET
+  count_history_unconfirmed = {SCHEMA}.stats_addresses.count_history_unconfirmed + EXCLUDED.count_history_unconfirmed,
+  count_unspent_unconfirmed = {SCHEMA}.stats_addresses.count_unspent_unconfirmed + EXCLUDED.count_unspent_unconfirmed,
+  received_unconfirmed = {SCHEMA}.stats_addresses.received_unconfirmed + EXCLUDED.received_unconfirmed,
+  sent_unconfirmed = {SCHEMA}.stats_addresses.sent_unconfirmed + EXCLUDED.sent_unconfirmed;
+
+
+-- Mempool, unconfirmed transactions have NULL in `block_height`.
+-- Unconfirmed columns in `stats_addresses` are changes on top of confirmed values.
+-- name: transactionsSelectUnconfirmed
+SELECT txid FROM {SCHEMA}.transactions WHERE block_height IS NULL;
+
+-- name: transactionsInputsOutputsSelectUnconfirmedSpenders
+SELECT
+  input_txid
+FROM
+  {SCHEMA}.transactions_inputs_outputs
+WHERE
+  (output_txid, output_vout) IN (VALUES {VALUES}) AND
+  input_txid IS NOT NULL AND
+  input_block_height IS NULL;
+
+-- name: transactionsSelectUnconfirmedDescendants
+WITH RECURSIVE descendants(txid) AS (
+  SELECT unnest($1::bytea[])
+  UNION
+  SELECT
+    tio.input_txid
+  FROM
+    {SCHEMA}.transactions_inputs_outputs AS tio, descendants
+  WHERE
+    tio.output_txid = descendants.txid AND
+    tio.input_txid IS NOT NULL AND
+    tio.input_block_height IS NULL
+)
+SELECT
+  txid
+FROM
+  {SCHEMA}.transactions
+WHERE
+  txid IN (SELECT txid FROM descendants) AND
+  block_height IS NULL;
+
+-- name: statsAddressesRemoveUnconfirmedHistory
+WITH history AS (
+  SELECT
+    address,
+    count(*) AS count,
+    sum(received) AS received,
+    sum(sent) AS sent
+  FROM
+    {SCHEMA}.address_history
+  WHERE
+    txid = ANY($1)
+  GROUP BY
+    address
+)
+UPDATE {SCHEMA}.stats_addresses
+SET
+  count_history_unconfirmed = count_history_unconfirmed - history.count,
+  received_unconfirmed = received_unconfirmed - history.received,
+  sent_unconfirmed = sent_unconfirmed - history.sent
+FROM history
+WHERE {SCHEMA}.stats_addresses.address = history.address
+RETURNING {SCHEMA}.stats_addresses.address;
+
+-- name: statsAddressesRemoveUnconfirmedUnspent
+WITH unspent AS (
+  SELECT
+    address,
+    count(*) AS count
+  FROM
+    {SCHEMA}.address_unspent
+  WHERE
+    txid = ANY($1)
+  GROUP BY
+    address
+)
+UPDATE {SCHEMA}.stats_addresses
+SET
+  count_unspent_unconfirmed = count_unspent_unconfirmed - unspent.count
+FROM unspent
+WHERE {SCHEMA}.stats_addresses.address = unspent.address;
+
+-- name: addressUnspentRestoreUnconfirmedSpent
+WITH restored AS (
+  INSERT INTO {SCHEMA}.address_unspent (
+    address, block_height, txid, vout, value
+  )
+  SELECT
+    addresses.address,
+    output_block_height,
+    output_txid,
+    output_vout,
+    (output_data::jsonb->>'value')::int8
+  FROM
+    {SCHEMA}.transactions_inputs_outputs,
+    jsonb_array_elements_text(output_data::jsonb->'addresses') AS addresses(address)
+  WHERE
+    input_txid = ANY($1) AND
+    NOT (output_txid = ANY($1))
+  RETURNING address
+), restored_count AS (
+  SELECT
+    address,
+    count(*) AS count
+  FROM restored
+  GROUP BY address
+)
+UPDATE {SCHEMA}.stats_addresses
+SET
+  count_unspent_unconfirmed = count_unspent_unconfirmed + restored_count.count
+FROM restored_count
+WHERE {SCHEMA}.stats_addresses.address = restored_count.address;
+
+-- name: transactionsInputsOutputsRemoveUnconfirmedOutputs
+DELETE FROM {SCHEMA}.transactions_inputs_outputs WHERE output_txid = ANY($1);
+
+-- name: transactionsInputsOutputsRemoveUnconfirmedInputs
+UPDATE {SCHEMA}.transactions_inputs_outputs
+SET
+  input_block_height = NULL,
+  input_txid = NULL,
+  input_vin = NULL,
+  input_data = NULL
+WHERE
+  input_txid = ANY($1);
+
+-- Address history and unspent removed by fkeys
+-- name: transactionsDeleteUnconfirmed
+DELETE FROM {SCHEMA}.transactions WHERE txid = ANY($1) AND block_height IS NULL;
+
+
+-- Reorg, remove orphaned block and restore outputs spent in it.
+-- Block removed from top to bottom, so outputs from later blocks already removed.
+-- name: blocksSelectHashByHeight
+SELECT hash FROM {SCHEMA}.blocks WHERE height = $1;
+
+-- name: transactionsSelectTxIdsByHeight
+SELECT txid FROM {SCHEMA}.transactions WHERE block_height = $1;
+
+-- name: statsAddressesRollbackHistory
+WITH history AS (
+  SELECT
+    address,
+    count(*) AS count,
+    sum(received) AS received,
+    sum(sent) AS sent
+  FROM
+    {SCHEMA}.address_history
+  WHERE
+    txid IN (SELECT txid FROM {SCHEMA}.transactions WHERE block_height = $1)
+  GROUP BY
+    address
+)
+UPDATE {SCHEMA}.stats_addresses
+SET
+  count_history_confirmed = count_history_confirmed - history.count,
+  received_confirmed = received_confirmed - history.received,
+  sent_confirmed = sent_confirmed - history.sent
+FROM history
+WHERE {SCHEMA}.stats_addresses.address = history.address
+RETURNING {SCHEMA}.stats_addresses.address;
+
+-- name: statsAddressesRollbackUnspent
+WITH unspent AS (
+  SELECT
+    address,
+    count(*) AS count
+  FROM
+    {SCHEMA}.address_unspent
+  WHERE
+    txid IN (SELECT txid FROM {SCHEMA}.transactions WHERE block_height = $1)
+  GROUP BY
+    address
+)
+UPDATE {SCHEMA}.stats_addresses
+SET
+  count_unspent_confirmed = count_unspent_confirmed - unspent.count
+FROM unspent
+WHERE {SCHEMA}.stats_addresses.address = unspent.address;
+
+-- name: addressUnspentRestoreSpent
+WITH restored AS (
+  INSERT INTO {SCHEMA}.address_unspent (
+    address, block_height, txid, vout, value
+  )
+  SELECT
+    addresses.address,
+    output_block_height,
+    output_txid,
+    output_vout,
+    (output_data::jsonb->>'value')::int8
+  FROM
+    {SCHEMA}.transactions_inputs_outputs,
+    jsonb_array_elements_text(output_data::jsonb->'addresses') AS addresses(address)
+  WHERE
+    input_txid IN (SELECT txid FROM {SCHEMA}.transactions WHERE block_height = $1) AND
+    output_block_height < $1
+  RETURNING address
+), restored_count AS (
+  SELECT
+    address,
+    count(*) AS count
+  FROM restored
+  GROUP BY address
+)
+UPDATE {SCHEMA}.stats_addresses
+SET
+  count_unspent_confirmed = count_unspent_confirmed + restored_count.count
+FROM restored_count
+WHERE {SCHEMA}.stats_addresses.address = restored_count.address;
+
+-- name: transactionsInputsOutputsRollbackOutputs
+DELETE FROM {SCHEMA}.transactions_inputs_outputs
+WHERE output_txid IN (SELECT txid FROM {SCHEMA}.transactions WHERE block_height = $1);
+
+-- name: transactionsInputsOutputsRollbackCoinbase
+DELETE FROM {SCHEMA}.transactions_inputs_outputs
+WHERE
+  input_txid IN (SELECT txid FROM {SCHEMA}.transactions WHERE block_height = $1) AND
+  output_txid IS NULL;
+
+-- name: transactionsInputsOutputsRollbackInputs
+UPDATE {SCHEMA}.transactions_inputs_outputs
+SET
+  input_block_height = NULL,
+  input_txid = NULL,
+  input_vin = NULL,
+  input_data = NULL
+WHERE
+  input_txid IN (SELECT txid FROM {SCHEMA}.transactions WHERE block_height = $1);
+
+-- name: statsAddressesDeleteEmpty
+DELETE FROM {SCHEMA}.stats_addresses
+WHERE
+  address = ANY($1) AND
+  count_history_confirmed = 0 AND
+  count_history_unconfirmed = 0;
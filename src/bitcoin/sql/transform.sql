@@ -82,6 +82,10 @@ CREATE UNIQUE INDEX txs_inout_input_txid_input_vin_idx ON {SCHEMA}.transactions_
 CREATE UNIQUE INDEX txs_inout_output_txid_output_vout_idx ON {SCHEMA}.transactions_inputs_outputs (output_txid, output_vout);
 
 
+-- Required for `blocks_transform` and `transactions` fkey.
+-- name: blocksHeightIdx
+CREATE UNIQUE INDEX blocks_height_idx ON {SCHEMA}.blocks (height);
+
 -- Call process function in loop from Rust in parallel.
 -- name: blocksProcessed
 SELECT
@@ -97,14 +101,10 @@ $BODY$
 DECLARE
   query_count int4 = 1;
   blk_processed boolean;
-  inputs_total {SCHEMA}.btc_value = 0;
-  outputs_total {SCHEMA}.btc_value = 0;
+  blk_inputs_total numeric = 0;
+  blk_outputs_total numeric = 0;
   tx_row RECORD;
-  tx_inputs_total {SCHEMA}.btc_value;
-  addresses jsonb;
-  row RECORD;
-  row_value {SCHEMA}.btc_value;
-  row_address text;
+  tx_inputs_total int8;
 BEGIN
   -- Check that block is not transformed yet.
   SELECT processed FROM {SCHEMA}.blocks WHERE height = blk_height FOR UPDATE INTO STRICT blk_processed;
@@ -122,117 +122,69 @@ BEGIN
     WHERE
       block_height = blk_height
   LOOP
-    tx_inputs_total = 0;
-    addresses := '{}'::jsonb;
+    -- Total value of spent outputs, except coinbase
+    query_count := query_count + 1;
+    SELECT
+      COALESCE(sum((output_data::jsonb->>'value')::int8), 0)::int8
+    FROM
+      {SCHEMA}.transactions_inputs_outputs
+    WHERE
+      input_txid = tx_row.txid AND output_txid IS NOT NULL
+    INTO STRICT tx_inputs_total;
 
-    -- Handle each input in transaction, except coinbase
+    -- Insert address history, sent from inputs and received in outputs
     query_count := query_count + 1;
-    FOR row IN
+    INSERT INTO {SCHEMA}.address_history (
+      address, block_height, txid, tx_index, time, received, sent
+    )
+    SELECT
+      changes.address,
+      blk_height,
+      tx_row.txid,
+      tx_row.index,
+      tx_row.time,
+      sum(changes.received)::int8,
+      sum(changes.sent)::int8
+    FROM (
       SELECT
-        output_data::jsonb
+        addresses.address,
+        0::int8 AS received,
+        (output_data::jsonb->>'value')::int8 AS sent
       FROM
-        {SCHEMA}.transactions_inputs_outputs
+        {SCHEMA}.transactions_inputs_outputs,
+        jsonb_array_elements_text(output_data::jsonb->'addresses') AS addresses(address)
       WHERE
         input_txid = tx_row.txid AND output_txid IS NOT NULL
-      ORDER BY
-        input_vin
-    LOOP
-      row_value := (row.output_data->>'value')::{SCHEMA}.btc_value;
-      tx_inputs_total := tx_inputs_total + row_value;
-
-      FOR row_address IN SELECT jsonb_array_elements_text(row.output_data->'addresses') LOOP
-        IF addresses ? row_address THEN
-          addresses := jsonb_set(
-            addresses,
-            ARRAY[row_address],
-            jsonb_set(
-              addresses->row_address,
-              '{sent}',
-              to_jsonb((((addresses->row_address)->>'sent')::{SCHEMA}.btc_value + row_value)::text)
-            )
-          );
-        ELSE
-          addresses := jsonb_set(
-            addresses,
-            ARRAY[row_address],
-            jsonb_build_object(
-              'received', '0',
-              'sent', to_jsonb(row.output_data->>'value')
-            ),
-            true
-          );
-        END IF;
-      END LOOP;
-    END LOOP;
-
-    -- Handle each output in transaction
-    query_count := query_count + 1;
-    FOR row IN
+      UNION ALL
       SELECT
-        input_txid,
-        output_vout,
-        output_data::jsonb
+        addresses.address,
+        (output_data::jsonb->>'value')::int8 AS received,
+        0::int8 AS sent
       FROM
-        {SCHEMA}.transactions_inputs_outputs
+        {SCHEMA}.transactions_inputs_outputs,
+        jsonb_array_elements_text(output_data::jsonb->'addresses') AS addresses(address)
       WHERE
         output_txid = tx_row.txid
-      ORDER BY
-        output_vout
-    LOOP
-      row_value := (row.output_data->>'value')::{SCHEMA}.btc_value;
-
-      FOR row_address IN SELECT jsonb_array_elements_text(row.output_data->'addresses') LOOP
-        IF addresses ? row_address THEN
-          addresses := jsonb_set(
-            addresses,
-            ARRAY[row_address],
-            jsonb_set(
-              addresses->row_address,
-              '{received}',
-              to_jsonb((((addresses->row_address)->>'received')::{SCHEMA}.btc_value + row_value)::text)
-            )
-          );
-        ELSE
-          addresses := jsonb_set(
-            addresses,
-            ARRAY[row_address],
-            jsonb_build_object(
-              'received', to_jsonb(row.output_data->>'value'),
-              'sent', '0'
-            ),
-            true
-          );
-        END IF;
-
-        -- TODO: Is it possible collect to some variable and insert in one query?
-        -- If output is not spent yet, insert it to unspent table.
-        IF row.input_txid IS NULL THEN
-          query_count := query_count + 1;
-          INSERT INTO {SCHEMA}.address_unspent (
-            address, block_height, txid, vout, value
-          ) VALUES (
-            row_address, blk_height, tx_row.txid, row.output_vout, row_value
-          );
-        END IF;
-      END LOOP;
-    END LOOP;
-
-    -- TODO: Is it possible generate some collection for insert as one query?
-    -- Insert address history
-    FOR row_address IN SELECT jsonb_object_keys(addresses) LOOP
-      query_count := query_count + 1;
-      INSERT INTO {SCHEMA}.address_history (
-        address, block_height, txid, tx_index, time, received, sent
-      ) VALUES (
-        row_address,
-        blk_height,
-        tx_row.txid,
-        tx_row.index,
-        tx_row.time,
-        ((addresses->row_address)->>'received')::{SCHEMA}.btc_value,
-        ((addresses->row_address)->>'sent')::{SCHEMA}.btc_value
-      );
-    END LOOP;
+    ) AS changes
+    GROUP BY
+      changes.address;
+
+    -- If output is not spent yet, insert it to unspent table.
+    query_count := query_count + 1;
+    INSERT INTO {SCHEMA}.address_unspent (
+      address, block_height, txid, vout, value
+    )
+    SELECT
+      addresses.address,
+      blk_height,
+      tx_row.txid,
+      output_vout,
+      (output_data::jsonb->>'value')::int8
+    FROM
+      {SCHEMA}.transactions_inputs_outputs,
+      jsonb_array_elements_text(output_data::jsonb->'addresses') AS addresses(address)
+    WHERE
+      output_txid = tx_row.txid AND input_txid IS NULL;
 
     -- Insert transaction with calculated `tx_inputs_total`
     query_count := query_count + 1;
@@ -251,8 +203,8 @@ BEGIN
       tx_row.outputs_total
     );
 
-    inputs_total := inputs_total + tx_inputs_total;
-    outputs_total := outputs_total + tx_row.outputs_total;
+    blk_inputs_total := blk_inputs_total + tx_inputs_total;
+    blk_outputs_total := blk_outputs_total + tx_row.outputs_total;
   END LOOP;
 
   -- Mark block as processed. Set total value for inputs and outputs.
@@ -260,8 +212,8 @@ BEGIN
   UPDATE {SCHEMA}.blocks
   SET
     processed = TRUE,
-    inputs_total = inputs_total,
-    outputs_total = outputs_total
+    inputs_total = blk_inputs_total,
+    outputs_total = blk_outputs_total
   WHERE
     height = blk_height;
 
@@ -279,6 +231,9 @@ DROP FUNCTION {SCHEMA}.blocks_transform (int4);
 -- name: blocksProcessedDrop
 ALTER TABLE {SCHEMA}.blocks DROP COLUMN processed;
 
+-- name: blocksInputsTotalNotNull
+ALTER TABLE {SCHEMA}.blocks ALTER COLUMN inputs_total SET NOT NULL;
+
 -- name: transactionsInitialDrop
 DROP TABLE {SCHEMA}.transactions_initial;
 
@@ -319,8 +274,8 @@ SELECT
   history.address,
   history.count, 0,
   COALESCE(unspent.count, 0), 0,
-  history.received, '0',
-  history.sent, '0'
+  history.received, 0,
+  history.sent, 0
 FROM
   history
 LEFT OUTER JOIN
@@ -345,7 +300,7 @@ REFERENCES {SCHEMA}.blocks(height) ON DELETE CASCADE;
 ALTER TABLE {SCHEMA}.stats_addresses ADD PRIMARY KEY (address);
 
 -- name: addressHistoryAddressIndexIdx
-CREATE INDEX address_history_address_index_idx ON {SCHEMA}.address_history (address, block_height ASC NULLS LAST, tx_index ASC NULL LAST);
+CREATE INDEX address_history_address_index_idx ON {SCHEMA}.address_history (address, block_height ASC NULLS LAST, tx_index ASC NULLS LAST);
 
 -- name: addressHistoryAddressFKey
 ALTER TABLE {SCHEMA}.address_history
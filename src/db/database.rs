@@ -185,9 +185,27 @@ impl DataBase {
         *self.stage.write().await = (name.into(), None);
     }
 
-    // pub async fn set_stage_with_progress<S: Into<String>>(&self, name: S, progress: f64) {
-    //     *self.stage.write().await = (name.into(), Some(progress));
-    // }
+    // Execute queries and save new stage to `schema_info` in one transaction,
+    // so saved stage always match to executed queries.
+    pub async fn execute_with_stage(&self, queries: &[&str], stage: &str) -> EmptyResult {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        for query in queries {
+            tx.batch_execute(query).await?;
+        }
+
+        let query = self.queries.get("base", "schemaInfoSetStage");
+        tx.execute(query, &[&stage]).await?;
+
+        tx.commit().await?;
+        self.set_stage(stage).await;
+        Ok(())
+    }
+
+    pub async fn set_stage_with_progress<S: Into<String>>(&self, name: S, progress: f64) {
+        *self.stage.write().await = (name.into(), Some(progress));
+    }
 
     pub async fn get_stage(&self) -> (String, Option<f64>) {
         let stage = self.stage.read().await;
@@ -219,6 +237,42 @@ impl DataBase {
     }
 }
 
+// Tests with real PostgreSQL, connection string from environment variable.
+// Every test use own schema, which is created again on every run. Such tests
+// are ignored by default, run with `cargo test -- --ignored`.
+#[cfg(test)]
+impl DataBase {
+    pub async fn new_test(schema: &str, version: u16, app_queries: StaticQueries) -> DataBase {
+        let conn_str = std::env::var("TELESCOPE_TEST_POSTGRES")
+            .expect("TELESCOPE_TEST_POSTGRES should be set for tests with PostgreSQL");
+
+        let mut queries = Queries::new();
+        queries.load(BASE_QUERIES, schema);
+        queries.load(app_queries, schema);
+
+        let conf = conn_str.parse::<Config>().unwrap();
+        let manager = PostgresConnectionManager::new(conf, NoTls);
+        let pool = Pool::builder().max_size(2).build_unchecked(manager);
+
+        let db = DataBase {
+            coin: "bitcoin".to_owned(),
+            chain: "main".to_owned(),
+            version,
+            sync_from: 0,
+            stage: RwLock::new(("#none".to_owned(), None)),
+            queries,
+            pool,
+        };
+
+        let query = format!("DROP SCHEMA IF EXISTS {} CASCADE", schema);
+        let client = db.pool.get().await.unwrap();
+        client.batch_execute(&query).await.unwrap();
+
+        db.validate_schema().await.unwrap();
+        db
+    }
+}
+
 #[macro_export]
 macro_rules! db_add_basic_methods {
     ($name:ident) => {
@@ -231,6 +285,14 @@ macro_rules! db_add_basic_methods {
             //     self.db.set_stage(name, progress).await
             // }
 
+            pub async fn set_stage_with_progress<S: Into<String>>(&self, name: S, progress: f64) {
+                self.db.set_stage_with_progress(name, progress).await
+            }
+
+            pub async fn save_stage(&self, name: &str) -> EmptyResult {
+                self.db.execute_with_stage(&[], name).await
+            }
+
             pub async fn get_stage(&self) -> (String, Option<f64>) {
                 self.db.get_stage().await
             }